@@ -0,0 +1,185 @@
+//! What --search-action does when a search pattern is found, and the
+//! webhook and capture it may start.
+
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::filter::StreamFilter;
+use crate::metrics::Direction;
+use crate::net::split_host_port;
+use crate::registry::Connection;
+
+/// How long a webhook may take before it is given up.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Posts in flight at once, hits past them are dropped.
+const MAX_WEBHOOKS: usize = 16;
+
+/// Chunks a capture may have waiting for the disk before it gives up.
+const CAPTURE_QUEUE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Action {
+    /// Write everything the connection carries from now on to --capture-dir.
+    Capture,
+    /// Post the hit to --webhook.
+    Webhook,
+    /// Close the connection.
+    Kill,
+    /// Swallow what the client sends from now on, like blocking mode does.
+    Block,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "capture" => Ok(Action::Capture),
+            "webhook" => Ok(Action::Webhook),
+            "kill" => Ok(Action::Kill),
+            "block" => Ok(Action::Block),
+            _ => Err(format!("unknown search action {:?}, expected capture, webhook, kill or block", s)),
+        }
+    }
+}
+
+/// Split `<pattern>=<action>[,<action>...]` into the pattern and its actions.
+pub(super) fn parse(spec: &str) -> Result<(&str, Vec<Action>), String> {
+    let (pattern, actions) = spec.rsplit_once('=')
+        .ok_or_else(|| format!("search action {:?} should look like <pattern>=<action>", spec))?;
+    let actions = actions.split(',').map(str::parse).collect::<Result<_, _>>()?;
+    Ok((pattern, actions))
+}
+
+/// An http endpoint the hits are posted to, `http://host:port/path`.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    addr: String,
+    path: String,
+    posts: Arc<Semaphore>,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("http://")
+            .ok_or_else(|| format!("{:?} should look like http://host:port/path", s))?;
+        let (addr, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        if split_host_port(addr).is_none() {
+            return Err(format!("{:?} has no port", s))
+        }
+        Ok(Self { addr: addr.to_owned(), path: path.to_owned(), posts: Arc::new(Semaphore::new(MAX_WEBHOOKS)) })
+    }
+}
+
+impl Webhook {
+    /// Post `body` in the background, a failure is only reported.
+    pub(super) fn send(self: &Arc<Self>, body: String) {
+        let permit = match self.posts.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                report!("webhook error: {:?}", format!("{} posts are pending, dropping a hit", MAX_WEBHOOKS));
+                return
+            }
+        };
+        let webhook = self.clone();
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(WEBHOOK_TIMEOUT, webhook.post(&body)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+            if let Err(e) = result {
+                report!("webhook error: {:?}", e.to_string());
+            }
+            drop(permit);
+        });
+    }
+
+    async fn post(&self, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path, self.addr, body.len(), body,
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response).lines().next().unwrap_or("").to_owned();
+        if status.split(' ').nth(1).is_some_and(|x| x.starts_with('2')) {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("the endpoint answered {:?}", status)))
+        }
+    }
+}
+
+/// Writes a direction of a connection to `<dir>/<capture>-<direction>.bin`
+/// once a capture action started it. The file is written by a task of its
+/// own, the chunks queue up for it.
+pub(super) struct Capture {
+    dir: Arc<PathBuf>,
+    direction: Direction,
+    /// `None` until the capture starts.
+    writer: Option<mpsc::Sender<Vec<u8>>>,
+    failed: bool,
+}
+
+impl Capture {
+    pub(super) fn new(dir: Arc<PathBuf>, direction: Direction) -> Self {
+        Self { dir, direction, writer: None, failed: false }
+    }
+
+    /// Start the task writing the capture `name`.
+    fn open(&self, name: &str) -> mpsc::Sender<Vec<u8>> {
+        let direction = match self.direction { Direction::Out => "out", Direction::In => "in" };
+        let dir = self.dir.clone();
+        let path = dir.join(format!("{}-{}.bin", name, direction));
+        let (writer, mut chunks) = mpsc::channel::<Vec<u8>>(CAPTURE_QUEUE);
+        tokio::spawn(async move {
+            let result: io::Result<()> = async {
+                tokio::fs::create_dir_all(&*dir).await?;
+                let mut file = tokio::fs::File::create(&path).await?;
+                while let Some(chunk) = chunks.recv().await {
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await
+            }.await;
+            if let Err(e) = result {
+                report!("capture error: {:?}", e.to_string());
+            }
+        });
+        writer
+    }
+}
+
+impl StreamFilter for Capture {
+    fn on_chunk(&mut self, conn: &Connection, chunk: &mut Vec<u8>) {
+        let name = match conn.capture() {
+            Some(name) if !self.failed => name,
+            _ => return,
+        };
+        if self.writer.is_none() {
+            self.writer = Some(self.open(name));
+        }
+        match self.writer.as_ref().map(|x| x.try_send(chunk.clone())) {
+            Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                report!("capture error: {:?}", "the disk falls behind, the capture stops here");
+            }
+            // the writer already reported why it stopped
+            Some(Err(mpsc::error::TrySendError::Closed(_))) => {}
+            _ => return,
+        }
+        self.writer = None;
+        self.failed = true;
+    }
+}
@@ -0,0 +1,200 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use kmp::kmp_find;
+
+use crate::net::split_host_port;
+use crate::registry::{Registry, RULES};
+
+/// Requests are cut off after this many bytes.
+const MAX_REQUEST: usize = 8192;
+
+pub(super) fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn connections(registry: &Registry) -> String {
+    json_list(registry.connections().iter().map(|conn| {
+        format!(
+            "{{\"id\":{},\"peer\":{},\"local_port\":{},\"backend\":{},\"bytes_out\":{},\"bytes_in\":{},\"blocking\":{},\"matched\":{},\"age_secs\":{}}}",
+            conn.id,
+            json_string(&conn.peer.to_string()),
+            conn.local_port,
+            json_string(&conn.backend),
+            conn.bytes_out.load(Ordering::Relaxed),
+            conn.bytes_in.load(Ordering::Relaxed),
+            match conn.blocking() { Some(b) => b.to_string(), None => String::from("null") },
+            conn.matched(),
+            conn.started.elapsed().as_secs(),
+        )
+    }))
+}
+
+/// Whether `backend` is `host:port` or `unix:<path>`.
+fn valid_backend(backend: &str) -> bool {
+    match backend.strip_prefix("unix:") {
+        Some(path) => !path.is_empty(),
+        None => split_host_port(backend).is_some_and(|(host, _)| !host.is_empty()),
+    }
+}
+
+/// Route a request, returning the status line and a json body.
+fn route(registry: &Registry, method: &str, path: &str, body: &str) -> (&'static str, String) {
+    const OK: &str = "200 OK";
+    const NOT_FOUND: &str = "404 Not Found";
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["connections"]) => (OK, connections(registry)),
+        ("POST", ["connections", id, "close"]) => {
+            match id.parse().ok().and_then(|id| registry.connection(id)) {
+                Some(conn) => {
+                    conn.kill();
+                    (OK, String::from("{\"closed\":true}"))
+                }
+                None => (NOT_FOUND, String::from("{\"error\":\"no such connection\"}")),
+            }
+        }
+        ("GET", ["backends"]) => (OK, json_list(registry.backends().iter().map(|x| json_string(x)))),
+        // the backend is the body, a unix socket path does not fit in a path segment
+        ("POST", ["backends"]) => {
+            let backend = body.trim();
+            if !valid_backend(backend) {
+                return ("400 Bad Request", String::from("{\"error\":\"expected host:port or unix:<path>\"}"))
+            }
+            (OK, format!("{{\"added\":{}}}", registry.add_backend(backend)))
+        }
+        ("DELETE", ["backends"]) => (OK, format!("{{\"removed\":{}}}", registry.remove_backend(body.trim()))),
+        ("GET", ["rules"]) => (OK, json_list(RULES.iter().map(|rule| {
+            format!("{{\"name\":{},\"enabled\":{}}}", json_string(rule), registry.rule_enabled(rule))
+        }))),
+        ("POST", ["rules", rule, action @ "enable"]) | ("POST", ["rules", rule, action @ "disable"]) => {
+            if registry.set_rule(rule, *action == "enable") {
+                (OK, format!("{{\"name\":{},\"enabled\":{}}}", json_string(rule), *action == "enable"))
+            } else {
+                (NOT_FOUND, String::from("{\"error\":\"no such rule\"}"))
+            }
+        }
+        _ => (NOT_FOUND, String::from("{\"error\":\"not found\"}")),
+    }
+}
+
+/// Read a request up to `MAX_REQUEST` bytes, with as much of its body as its
+/// content-length says.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        if let Some(idx) = kmp_find(b"\r\n\r\n", &request) {
+            let head = String::from_utf8_lossy(&request[..idx]).to_ascii_lowercase();
+            let length = head.lines()
+                .find_map(|x| x.strip_prefix("content-length:"))
+                .and_then(|x| x.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= idx + 4 + length {
+                return Ok(request)
+            }
+        }
+        if request.len() >= MAX_REQUEST {
+            return Ok(request)
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(request)
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+}
+
+async fn respond<S>(mut stream: S, registry: Arc<Registry>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = read_request(&mut stream).await?;
+    let line = String::from_utf8_lossy(request.split(|&b| b == b'\r' || b == b'\n').next().unwrap_or(&[])).into_owned();
+    let mut parts = line.split(' ');
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let body = match kmp_find(b"\r\n\r\n", &request) {
+        Some(idx) => String::from_utf8_lossy(&request[idx + 4..]).into_owned(),
+        None => String::new(),
+    };
+
+    let (status, body) = route(&registry, method, path, &body);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn handle<S>(stream: S, registry: &Arc<Registry>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let registry = registry.clone();
+    tokio::spawn(async move {
+        if let Err(e) = respond(stream, registry).await {
+            report!("admin response error: {:?}", e.to_string());
+        }
+    });
+}
+
+/// Serve the admin api on a loopback `ip:port` or on `unix:<path>`.
+pub(super) async fn serve(addr: String, registry: Arc<Registry>) -> io::Result<()> {
+    let date = chrono::Local::now();
+    if let Some(path) = addr.strip_prefix("unix:") {
+        let listener = crate::net::bind_unix(path)?;
+        report!("[{}] admin api is listening on {}", date.format("%m-%d %H:%M"), addr);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => handle(stream, &registry),
+                    Err(e) => {
+                        report!("admin accept error: {:?}", e.to_string());
+                        tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        });
+    } else {
+        let socket_addr: SocketAddr = addr.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "admin address must be ip:port or unix:<path>"))?;
+        if !socket_addr.ip().is_loopback() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "admin api only listens on a loopback address"))
+        }
+        let listener = TcpListener::bind(socket_addr).await?;
+        report!("[{}] admin api is listening on http://{}", date.format("%m-%d %H:%M"), addr);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => handle(stream, &registry),
+                    Err(e) => {
+                        report!("admin accept error: {:?}", e.to_string());
+                        tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::filter::{FilterFactory, StreamFilter};
+use crate::metrics::Direction;
+use crate::options::Options;
+use crate::registry::{Connection, Event, EventListener, Registry};
+use crate::service::{self, RemoteSelector, Service};
+use crate::{admin, first_bytes, login, metrics, mux, net, script, search, transparent, tui, tunnel};
+
+/// Sets up a `Forwarder`.
+#[derive(Default)]
+pub struct Builder {
+    options: Options,
+    listen: Vec<String>,
+    remotes: Vec<String>,
+    selector: Option<Arc<RemoteSelector>>,
+    filters: Vec<(Direction, Arc<FilterFactory>)>,
+    events: Option<Arc<EventListener>>,
+}
+
+impl Builder {
+    /// A builder with every option off, give it at least one address to listen on.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A builder configured like the command line would with `options`.
+    pub fn from_options(options: Options) -> Self {
+        Self { options, ..Self::default() }
+    }
+
+    /// Every option, for the ones without a method of their own.
+    pub fn options(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Also listen on `addr`, `ip:port`, `[ipv6]:port` or `unix:<path>`.
+    pub fn listen(mut self, addr: &str) -> Self {
+        self.listen.push(addr.to_owned());
+        self
+    }
+
+    /// Also forward to `addr`, `host:port` or `unix:<path>`. Several remotes
+    /// are used in round robin order.
+    pub fn remote(mut self, addr: &str) -> Self {
+        self.remotes.push(addr.to_owned());
+        self
+    }
+
+    /// Pick the remote of every client with `selector` instead of round robin,
+    /// it gets the client address and the local address the client reached.
+    pub fn remote_selector<F>(mut self, selector: F) -> Self
+    where
+        F: Fn(SocketAddr, SocketAddr) -> Option<String> + Send + Sync + 'static,
+    {
+        self.selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Run one direction of every connection through a filter made by `factory`.
+    pub fn filter<F>(mut self, direction: Direction, factory: F) -> Self
+    where
+        F: Fn(&Connection) -> Box<dyn StreamFilter> + Send + Sync + 'static,
+    {
+        self.filters.push((direction, Arc::new(factory)));
+        self
+    }
+
+    /// Call `listener` with everything that happens on the connections.
+    pub fn on_event<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.events = Some(Arc::new(listener));
+        self
+    }
+
+    /// Bind the listeners and start the side services (metrics, admin api,
+    /// tui, tunnel server) that are configured.
+    pub async fn build(self) -> io::Result<Forwarder> {
+        let Builder { mut options, listen, remotes, selector, mut filters, events } = self;
+        for path in &options.script {
+            let script = script::Script::load(path)?;
+            for direction in [Direction::Out, Direction::In] {
+                let script = script.clone();
+                filters.push((direction, Arc::new(move |conn: &Connection| script.filter(conn, direction))));
+            }
+        }
+        #[cfg(feature = "wasm")]
+        for path in &options.wasm {
+            let plugin = crate::plugin::Plugin::load(path)?;
+            for direction in [Direction::Out, Direction::In] {
+                let plugin = plugin.clone();
+                filters.push((direction, Arc::new(move |conn: &Connection| plugin.filter(conn, direction))));
+            }
+        }
+        #[cfg(not(feature = "wasm"))]
+        if let Some(path) = options.wasm.first() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{}: built without the wasm feature", path)));
+        }
+        let password = options.password.take().map(Arc::new);
+        // the auto-login pages are no DVR protocol
+        options.blocking_mode &= password.is_none();
+        // the rules first, then the routes
+        let mut rules = options.first_bytes.clone();
+        rules.extend(options.route.iter().cloned().map(first_bytes::Rule::from));
+        let first_bytes = if rules.is_empty() {
+            None
+        } else {
+            Some(first_bytes::Rules::new(rules, options.first_bytes_timeout.map(Duration::from_millis)))
+        };
+        // the clients of a proxy only talk once the remote is connected
+        if first_bytes.is_some() && (options.socks5 || options.http_connect) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--first-bytes and --route do not work with --socks5 or --http-connect"));
+        }
+        if options.tunnel_listen.is_some() && first_bytes.as_ref().is_some_and(first_bytes::Rules::redirects) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--first-bytes and --route can not redirect clients of --tunnel-listen"));
+        }
+        report!("search pattern {} {:?}", if options.pattern_or { "or" } else { "and" }, options.search);
+        if !options.search_regex.is_empty() {
+            report!("search regex {:?}", options.search_regex);
+        }
+        let search = Arc::new(search::Patterns::from_options(&options)?);
+        let profiles = match &options.login_profiles {
+            Some(path) => {
+                let profiles = login::Profiles::load(path)?;
+                report!("login profiles {:?}", profiles.names());
+                profiles
+            }
+            None => login::Profiles::default(),
+        };
+
+        let metrics = Arc::new(metrics::Metrics::default());
+        if let Some(addr) = options.metrics_listen.take() {
+            metrics::serve(addr, metrics.clone()).await?;
+        }
+
+        let mut local_addrs = Vec::new();
+        let mut unix_paths: Vec<String> = options.local_unix.iter().cloned().collect();
+        if let Some(port) = options.local_port {
+            for ip in &options.local_ip {
+                local_addrs.extend(net::resolve(ip, port).await?);
+            }
+        }
+        let mut listen_names: Vec<String> = options.local_ip.iter().map(|ip| net::join_host_port(ip, options.local_port.unwrap_or(0))).collect();
+        listen_names.extend(options.local_unix.iter().map(|path| format!("unix:{}", path)));
+        for addr in listen {
+            if let Some(path) = addr.strip_prefix("unix:") {
+                unix_paths.push(path.to_owned());
+            } else {
+                let (host, port) = net::split_host_port(&addr)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} has no port", addr)))?;
+                local_addrs.extend(net::resolve(host, port).await?);
+            }
+            listen_names.push(addr);
+        }
+        if listen_names.is_empty() && options.tunnel_server.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "nothing to listen on"))
+        }
+
+        let rule = Arc::new(match (listen_names.is_empty(), &options.tunnel_server) {
+            (false, _) => listen_names.join(","),
+            (true, Some(server)) => format!("tunnel:{}", server),
+            (true, None) => String::new(),
+        });
+        let fixed_remote = !options.transparent && !options.tproxy && !options.socks5 && !options.http_connect && options.tunnel_listen.is_none();
+        let mut backends = match (&options.remote_ip, options.remote_port, &options.remote_unix) {
+            (_, _, Some(path)) if fixed_remote => vec![format!("unix:{}", path)],
+            (Some(ip), Some(port), _) if fixed_remote => vec![net::join_host_port(ip, port)],
+            _ => Vec::new(),
+        };
+        backends.extend(remotes);
+        let registry = Arc::new(Registry::new(backends));
+        if let Some(listener) = events {
+            registry.set_listener(move |event| listener(event));
+        }
+        if let Some(addr) = options.admin_listen.take() {
+            admin::serve(addr, registry.clone()).await?;
+        }
+
+        if options.http_connect && options.allow.is_empty() {
+            report!("no --allow is given, the http proxy will connect anywhere");
+        }
+
+        let date = chrono::Local::now();
+        report!("[{}] service is starting ...", date.format("%m-%d %H:%M"));
+
+        if options.tui {
+            tui::start(registry.clone())?;
+        }
+
+        let tunnel = match &options.tunnel_listen {
+            Some(addr) => Some(tunnel::TunnelServer::listen(addr, options.psk.as_deref().unwrap_or_default()).await?),
+            None => None,
+        };
+
+        // a dual-stack `::` would take the port away from an ipv4 address next to it
+        let only_v6 = local_addrs.iter().any(SocketAddr::is_ipv4);
+        let mut listeners = Vec::new();
+        for addr in &local_addrs {
+            let listener = if options.tproxy {
+                transparent::bind_tproxy(*addr)?
+            } else {
+                net::bind(*addr, only_v6)?
+            };
+            let date = chrono::Local::now();
+            report!("[{}] listening on {}", date.format("%m-%d %H:%M"), listener.local_addr()?);
+            listeners.push(listener);
+        }
+        let mut unix_listeners = Vec::new();
+        for path in &unix_paths {
+            unix_listeners.push(net::bind_unix(path)?);
+            let date = chrono::Local::now();
+            report!("[{}] listening on unix:{}", date.format("%m-%d %H:%M"), path);
+        }
+
+        // only the tunnel agent runs without a listener
+        let agent = if listen_names.is_empty() { options.tunnel_server.clone() } else { None };
+        let service = Arc::new(Service {
+            options,
+            password,
+            search,
+            rule,
+            metrics,
+            registry,
+            tunnel,
+            mux: mux::Dialer::default(),
+            selector,
+            filters: Arc::new(filters),
+            first_bytes,
+            profiles,
+        });
+        Ok(Forwarder { service, listeners, unix_listeners, agent })
+    }
+}
+
+/// A configured forwarder, see `Builder`.
+pub struct Forwarder {
+    service: Arc<Service>,
+    listeners: Vec<TcpListener>,
+    unix_listeners: Vec<UnixListener>,
+    agent: Option<String>,
+}
+
+impl Forwarder {
+    /// The connections, backends and rules, which can be changed while running.
+    pub fn registry(&self) -> Arc<Registry> {
+        self.service.registry.clone()
+    }
+
+    /// Where the tcp listeners are bound, useful after listening on port 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|x| x.local_addr().ok()).collect()
+    }
+
+    /// Accept and forward connections, a failed accept is reported and
+    /// retried after a pause.
+    pub async fn run(self) -> io::Result<()> {
+        let Forwarder { service, listeners, unix_listeners, agent } = self;
+        if let Some(server) = agent {
+            let psk = service.options.psk.clone().unwrap_or_default();
+            tunnel::run_agent(server, psk, move |stream, client, local| {
+                tokio::spawn(service::accept_stream(service.clone(), stream, client, Some(local), "tunnel"));
+            }).await;
+            return Ok(());
+        }
+
+        let (failed, mut failures) = tokio::sync::mpsc::unbounded_channel();
+        for listener in listeners {
+            let service = service.clone();
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                let _ = failed.send(service::serve(service, listener).await);
+            });
+        }
+        for listener in unix_listeners {
+            let service = service.clone();
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                let _ = failed.send(service::serve_unix(service, listener).await);
+            });
+        }
+        match failures.recv().await {
+            Some(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Stream compression between two tcpforward instances.
+//!
+//! The dialing side opens every connection with `TFZ`, a count and the
+//! algorithms it offers; the accepting side answers with the one it picked, or
+//! 0 to leave the stream uncompressed. Compressed bytes follow without any
+//! framing, each side flushes its encoder after every chunk it reads so
+//! interactive protocols are not held back.
+//!
+//! Coding goes one step at a time, no step takes or gives more than a chunk,
+//! and its output is written before the next step. A stream that inflates a
+//! lot therefore waits for its reader instead of piling up in memory.
+
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use zstd::stream::raw::{DParameter, InBuffer, Operation, OutBuffer};
+
+const MAGIC: &[u8; 3] = b"TFZ";
+
+/// Plaintext read per chunk.
+const CHUNK: usize = 16 * 1024;
+
+/// Largest zstd window a peer may ask for, 8 MiB.
+const MAX_WINDOW_LOG: u32 = 23;
+
+/// How long the peer has to answer the negotiation.
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Zstd,
+    Deflate,
+}
+
+impl Algorithm {
+    const ALL: [Algorithm; 2] = [Algorithm::Zstd, Algorithm::Deflate];
+
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Zstd => 1,
+            Algorithm::Deflate => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.id() == id)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::Deflate => "deflate",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.iter().copied().find(|x| x.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unsupported compression {:?}, expected zstd or deflate", s))
+    }
+}
+
+/// Byte counts of one compressed stream, both directions together.
+#[derive(Debug, Default)]
+pub(super) struct Stats {
+    algorithm: Option<&'static str>,
+    plain: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl Stats {
+    /// e.g. `zstd 1048576 -> 20480 bytes (51.20x)`
+    pub(super) fn summary(&self) -> String {
+        let plain = self.plain.load(Ordering::Relaxed);
+        let compressed = self.compressed.load(Ordering::Relaxed);
+        let ratio = if compressed == 0 { 1.0 } else { plain as f64 / compressed as f64 };
+        format!("{} {} -> {} bytes ({:.2}x)", self.algorithm.unwrap_or("none"), plain, compressed, ratio)
+    }
+}
+
+/// One direction of a compressed stream.
+enum Coder {
+    Plain,
+    ZstdEncoder(zstd::stream::raw::Encoder<'static>),
+    ZstdDecoder(zstd::stream::raw::Decoder<'static>),
+    DeflateEncoder(flate2::Compress),
+    DeflateDecoder(flate2::Decompress),
+}
+
+impl Coder {
+    fn encoder(algorithm: Option<Algorithm>) -> io::Result<Self> {
+        Ok(match algorithm {
+            None => Coder::Plain,
+            Some(Algorithm::Zstd) => Coder::ZstdEncoder(zstd::stream::raw::Encoder::new(3)?),
+            Some(Algorithm::Deflate) => Coder::DeflateEncoder(flate2::Compress::new(flate2::Compression::default(), false)),
+        })
+    }
+
+    fn decoder(algorithm: Option<Algorithm>) -> io::Result<Self> {
+        Ok(match algorithm {
+            None => Coder::Plain,
+            Some(Algorithm::Zstd) => {
+                let mut decoder = zstd::stream::raw::Decoder::new()?;
+                decoder.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG))?;
+                Coder::ZstdDecoder(decoder)
+            }
+            Some(Algorithm::Deflate) => Coder::DeflateDecoder(flate2::Decompress::new(false)),
+        })
+    }
+
+    /// Code as much of `input` as fits into `output`, flushing the stream, or
+    /// ending it with `finish`, once `input` is used up. Returns how much was
+    /// used and produced; a full `output` means there may be more to come.
+    fn step(&mut self, input: &[u8], output: &mut [u8], finish: bool) -> io::Result<(usize, usize)> {
+        fn invalid<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+        match self {
+            Coder::Plain => {
+                let n = input.len().min(output.len());
+                output[..n].copy_from_slice(&input[..n]);
+                Ok((n, n))
+            }
+            Coder::ZstdEncoder(x) => {
+                let mut input_buf = InBuffer::around(input);
+                let mut output_buf = OutBuffer::around(output);
+                x.run(&mut input_buf, &mut output_buf)?;
+                if input_buf.pos() == input.len() {
+                    if finish { x.finish(&mut output_buf, true)?; } else { x.flush(&mut output_buf)?; }
+                }
+                Ok((input_buf.pos(), output_buf.pos()))
+            }
+            Coder::ZstdDecoder(x) => {
+                let mut input_buf = InBuffer::around(input);
+                let mut output_buf = OutBuffer::around(output);
+                x.run(&mut input_buf, &mut output_buf)?;
+                Ok((input_buf.pos(), output_buf.pos()))
+            }
+            Coder::DeflateEncoder(x) => {
+                let (total_in, total_out) = (x.total_in(), x.total_out());
+                let flush = if finish { flate2::FlushCompress::Finish } else { flate2::FlushCompress::Sync };
+                x.compress(input, output, flush).map_err(invalid)?;
+                Ok(((x.total_in() - total_in) as usize, (x.total_out() - total_out) as usize))
+            }
+            Coder::DeflateDecoder(x) => {
+                let (total_in, total_out) = (x.total_in(), x.total_out());
+                x.decompress(input, output, flate2::FlushDecompress::None).map_err(invalid)?;
+                Ok(((x.total_in() - total_in) as usize, (x.total_out() - total_out) as usize))
+            }
+        }
+    }
+
+    /// Code all of `input` into `writer` a chunk at a time, each chunk is
+    /// written before the next one is coded. Returns the bytes written.
+    async fn pump<W>(&mut self, mut input: &[u8], output: &mut [u8], finish: bool, writer: &mut W) -> io::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        loop {
+            // a few bytes can decode to a lot, let other tasks run in between
+            tokio::task::consume_budget().await;
+            let (used, produced) = self.step(input, output, finish)?;
+            if used == 0 && produced == 0 && !input.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "compress: data past the end of the stream"))
+            }
+            input = &input[used..];
+            writer.write_all(&output[..produced]).await?;
+            written += produced as u64;
+            if input.is_empty() && produced < output.len() {
+                return Ok(written)
+            }
+        }
+    }
+}
+
+fn wrap<S>(conn: S, algorithm: Option<Algorithm>) -> io::Result<(DuplexStream, Arc<Stats>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stats = Arc::new(Stats { algorithm: algorithm.map(Algorithm::name), ..Stats::default() });
+    let mut encoder = Coder::encoder(algorithm)?;
+    let mut decoder = Coder::decoder(algorithm)?;
+
+    let (user, link_side) = tokio::io::duplex(CHUNK);
+    let (mut plain_reader, mut plain_writer) = tokio::io::split(link_side);
+    let (mut conn_reader, mut conn_writer) = tokio::io::split(conn);
+
+    let counting = stats.clone();
+    tokio::spawn(async move {
+        let result: io::Result<()> = async {
+            let mut buf = vec![0u8; CHUNK];
+            let mut output = vec![0u8; CHUNK];
+            loop {
+                let n = plain_reader.read(&mut buf).await?;
+                if n == 0 {
+                    break
+                }
+                counting.plain.fetch_add(n as u64, Ordering::Relaxed);
+                let written = encoder.pump(&buf[..n], &mut output, false, &mut conn_writer).await?;
+                counting.compressed.fetch_add(written, Ordering::Relaxed);
+            }
+            let written = encoder.pump(&[], &mut output, true, &mut conn_writer).await?;
+            counting.compressed.fetch_add(written, Ordering::Relaxed);
+            conn_writer.shutdown().await
+        }.await;
+        if let Err(e) = result {
+            report!("compress error: {:?}", e.to_string());
+        }
+    });
+
+    let counting = stats.clone();
+    tokio::spawn(async move {
+        let result: io::Result<()> = async {
+            let mut buf = vec![0u8; CHUNK];
+            let mut output = vec![0u8; CHUNK];
+            loop {
+                let n = conn_reader.read(&mut buf).await?;
+                if n == 0 {
+                    break
+                }
+                counting.compressed.fetch_add(n as u64, Ordering::Relaxed);
+                let written = decoder.pump(&buf[..n], &mut output, false, &mut plain_writer).await?;
+                counting.plain.fetch_add(written, Ordering::Relaxed);
+            }
+            plain_writer.shutdown().await
+        }.await;
+        if let Err(e) = result {
+            report!("decompress error: {:?}", e.to_string());
+        }
+    });
+
+    Ok((user, stats))
+}
+
+/// Offer `algorithm` on `conn` and compress it if the peer agrees.
+pub(super) async fn dial<S>(mut conn: S, algorithm: Algorithm) -> io::Result<(DuplexStream, Arc<Stats>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let offer = [MAGIC[0], MAGIC[1], MAGIC[2], 1, algorithm.id()];
+    let picked = tokio::time::timeout(NEGOTIATE_TIMEOUT, async {
+        conn.write_all(&offer).await?;
+        conn.read_u8().await
+    }).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "compress: no answer to the offer"))??;
+    wrap(conn, Algorithm::from_id(picked))
+}
+
+/// Answer the offer of the dialing side and compress `conn` accordingly.
+pub(super) async fn accept<S>(mut conn: S) -> io::Result<(DuplexStream, Arc<Stats>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let picked = tokio::time::timeout(NEGOTIATE_TIMEOUT, async {
+        let mut header = [0u8; 4];
+        conn.read_exact(&mut header).await?;
+        if &header[..3] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "compress: peer did not offer compression"))
+        }
+        let mut offered = vec![0u8; header[3] as usize];
+        conn.read_exact(&mut offered).await?;
+        let picked = offered.iter().copied().find_map(Algorithm::from_id);
+        conn.write_u8(picked.map_or(0, Algorithm::id)).await?;
+        Ok(picked)
+    }).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "compress: no offer"))??;
+    wrap(conn, picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn both_algorithms_round_trip() {
+        for algorithm in Algorithm::ALL {
+            let (a, b) = tokio::io::duplex(CHUNK);
+            let (dialed, accepted) = tokio::join!(dial(a, algorithm), accept(b));
+            let ((mut dialed, stats), (mut accepted, _)) = (dialed.unwrap(), accepted.unwrap());
+            let sent: Vec<u8> = (0..1_000_000u32).map(|x| (x % 251) as u8).collect();
+            let sending = sent.clone();
+            tokio::spawn(async move {
+                dialed.write_all(&sending).await.unwrap();
+                dialed.shutdown().await.unwrap();
+            });
+            let mut received = Vec::new();
+            accepted.read_to_end(&mut received).await.unwrap();
+            assert!(received == sent, "{} does not round trip", algorithm.name());
+            assert!(stats.compressed.load(Ordering::Relaxed) < sent.len() as u64 / 10, "{}", stats.summary());
+        }
+    }
+
+    #[tokio::test]
+    async fn inflating_waits_for_the_reader() {
+        for algorithm in Algorithm::ALL {
+            // 16 MiB of zeros fit in a few kilobytes
+            let mut encoder = Coder::encoder(Some(algorithm)).unwrap();
+            let mut compressed = Vec::new();
+            let zeros = vec![0u8; CHUNK];
+            let mut output = vec![0u8; CHUNK];
+            for _ in 0..1024 {
+                encoder.pump(&zeros, &mut output, false, &mut compressed).await.unwrap();
+            }
+            encoder.pump(&[], &mut output, true, &mut compressed).await.unwrap();
+            assert!(compressed.len() < 1024 * 1024);
+
+            // nobody reads, so decoding stops once the pipe is full
+            let (mut writer, _reader) = tokio::io::duplex(CHUNK);
+            let mut decoder = Coder::decoder(Some(algorithm)).unwrap();
+            let pumping = decoder.pump(&compressed, &mut output, false, &mut writer);
+            assert!(tokio::time::timeout(Duration::from_millis(100), pumping).await.is_err());
+
+            // with a reader it all comes out
+            let (mut writer, mut reader) = tokio::io::duplex(CHUNK);
+            let counting = tokio::spawn(async move {
+                let mut buf = vec![0u8; CHUNK];
+                let mut total = 0;
+                loop {
+                    match reader.read(&mut buf).await.unwrap() {
+                        0 => return total,
+                        n => total += n,
+                    }
+                }
+            });
+            let mut decoder = Coder::decoder(Some(algorithm)).unwrap();
+            decoder.pump(&compressed, &mut output, false, &mut writer).await.unwrap();
+            drop(writer);
+            assert_eq!(counting.await.unwrap(), 1024 * CHUNK);
+        }
+    }
+}
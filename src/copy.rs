@@ -1,10 +1,17 @@
+//! The copy loop behind `process_conn`: relays one direction of a connection
+//! through a chain of `StreamFilter`s. The built-in filters for the request
+//! log and blocking mode live here too.
+
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use std::future::Future;
 use std::io;
 use kmp::kmp_find;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::sync::Arc;
+use crate::filter::StreamFilter;
+use crate::registry::{Connection, EventKind, Registry};
 
 
 macro_rules! ready {
@@ -16,113 +23,105 @@ macro_rules! ready {
     };
 }
 
-#[derive(Debug)]
-pub(super) struct CopyBuffer<'a> {
+/// State of one direction being copied, drive it with `poll_copy`.
+pub struct CopyBuffer {
     read_done: bool,
     pos: usize,
-    cap: usize,
     amt: u64,
     buf: Vec<u8>,
-    client: &'a mut crate::Client,
-    password_segment: Option<String>,
-    replacement: Vec<(std::path::PathBuf, Vec<u8>, Vec<u8>)>,
-    constants_present: bool,
+    chunk: Vec<u8>,
+    conn: Arc<Connection>,
+    filters: Vec<Box<dyn StreamFilter>>,
 }
 
 
-fn replace(from: &[u8], to: &[u8], content: &mut Vec<u8>, length: usize) {
-    let search: Option<usize> = kmp_find(from, &content);
+pub(super) fn replace(from: &[u8], to: &[u8], content: &mut Vec<u8>, length: usize) -> bool {
+    let search: Option<usize> = kmp_find(from, content);
     if let Some(idx) = search {
         if idx + from.len() >= length || idx + to.len() >= length {
-            return
+            return false
         }
         let mut new_vec = Vec::from(&content[0 .. idx]);
         new_vec.extend(to);
         new_vec.extend(&content[idx + from.len() .. ]);
         *content = new_vec;
+        return true
     }
+    false
 }
 
-fn modify_buffer(buffer: &mut Vec<u8>, length: usize, password_segment: &str) -> isize {
-    let replaces: [[&[u8]; 2]; 2] = [
-                    [b"{this._beforeLogin()}", b"{this._beforeLogin();this._onLogin()}"],
-                    [b"s=o.getValue(),r=n.getValue()", password_segment.as_bytes()],
-                ];
-    
-
-    let old_length = buffer.len();
-
-    replace(b"X-Frame-Options: SAMEORIGIN\r\n", b"", buffer, length);
-
-    let diff = old_length - buffer.len();
-
-    let length = length - diff;
-
-    let old_buffer = buffer.clone();
-    let old_length = old_buffer.len();
+/// Logs and records the http request lines a client sends.
+pub(super) struct RequestLog {
+    registry: Arc<Registry>,
+}
 
-    for [from, to] in &replaces {
-        replace(from, to, buffer, length);
+impl RequestLog {
+    pub(super) fn new(client: &crate::Client) -> Self {
+        Self { registry: client.registry.clone() }
     }
+}
 
-    let new_length = buffer.len();
-
-    if new_length != old_length {
-        replace(b"CONTENT-LENGTH: 6236", format!("CONTENT-LENGTH: {}", 6236 + new_length - old_length).as_bytes(), buffer, length + new_length - old_length);
-        // println!("come on {} {}", std::str::from_utf8(&old_buffer).unwrap(), std::str::from_utf8(buffer).unwrap());
+impl StreamFilter for RequestLog {
+    fn on_chunk(&mut self, conn: &Connection, chunk: &mut Vec<u8>) {
+        if chunk.starts_with(b"GET ") || chunk.starts_with(b"POST") {
+            let line: String = chunk.iter().take_while(|&&c| c != b'\r').map(|&c| c as char).collect();
+            report!("{}", line);
+            self.registry.record(EventKind::Request, conn.id, line)
+        }
     }
+}
 
-    return new_length as isize - old_length as isize - diff as isize;
+/// Blocking mode: swallows whatever a client sends once its first bytes or
+/// a search action blocked the connection. In --blocking-mode the first chunk
+/// decides, the DVR protocol starts with `#` or `~` and anything else blocks.
+/// A client may wait for the remote as long as it likes before that.
+#[derive(Default)]
+pub(super) struct Blocking {
+    judged: bool,
 }
 
-fn log(data: &[u8], client: &crate::Client) {
-    let date = chrono::Local::now();
-    eprint!("[{}][{:?}]{}..{}:", date.format("%m-%d %H:%M"), client, client.pos, client.pos + data.len());
-    for b in data {
-        eprint!("{:02x}", b)
+impl StreamFilter for Blocking {
+    fn on_chunk(&mut self, conn: &Connection, chunk: &mut Vec<u8>) {
+        if !std::mem::replace(&mut self.judged, true) && conn.blocking() == Some(false) && chunk.first().is_some_and(|&x| x != 0x23 && x != 0x7e) {
+            conn.set_blocking();
+        }
+        if conn.blocking() == Some(true) {
+            chunk.clear()
+        }
     }
-    eprintln!()
 }
 
-impl<'a> CopyBuffer<'a> {
-    pub(super) fn new(client: &'a mut crate::Client, password: Option<&str>) -> Self {
-        use std::fs::{read_dir, File};
-        use std::path::Path;
-        use std::io::prelude::*;
-        let mut replacement = Vec::new();
-        for dir in read_dir("replacement").into_iter().flatten().flatten() {
-            let path = dir.path();
-            let from_path = Path::new("replacement").join(&path).join("from.txt");
-            let mut from = match File::open(from_path) {
-                Ok(x) => x,
-                _ => continue,
-            };
-            let to_path = Path::new("replacement").join(&path).join("to.txt");
-            let mut to = match File::open(to_path) {
-                Ok(x) => x,
-                _ => continue,
-            };
-            let mut from_buffer = Vec::new();
-            from.read_to_end(&mut from_buffer).unwrap();
-            let mut to_buffer = Vec::new();
-            to.read_to_end(&mut to_buffer).unwrap();
-            replacement.push((path, from_buffer, to_buffer));
-        }
+impl CopyBuffer {
+    /// A buffer running every chunk of `conn` through `filters`, in order.
+    pub fn new(conn: Arc<Connection>, filters: Vec<Box<dyn StreamFilter>>) -> Self {
         Self {
             read_done: false,
             pos: 0,
-            cap: 0,
             amt: 0,
-            // buf: vec![0; 65536].into_boxed_slice(),
             buf: vec![0; 65536],
-            client,
-            password_segment: password.map(|x| format!("s='admin',r='{}'", x)),
-            replacement,
-            constants_present: false,
+            chunk: Vec::new(),
+            conn,
+            filters,
         }
     }
 
-    pub(super) fn poll_copy<R, W>(
+    /// Run `chunk` through the filters from the `from`th on. A filter that
+    /// panics fails the copy, which closes the connection.
+    fn pass(&mut self, from: usize, chunk: &mut Vec<u8>) -> io::Result<()> {
+        for filter in &mut self.filters[from..] {
+            if chunk.is_empty() {
+                break
+            }
+            let conn = &self.conn;
+            std::panic::catch_unwind(AssertUnwindSafe(|| filter.on_chunk(conn, chunk)))
+                .map_err(|_| io::Error::other("a filter panicked"))?;
+        }
+        Ok(())
+    }
+
+    /// Copy from `reader` to `writer` until `reader` is done, returning how
+    /// many bytes were written.
+    pub fn poll_copy<R, W>(
         &mut self,
         cx: &mut Context<'_>,
         mut reader: Pin<&mut R>,
@@ -133,87 +132,43 @@ impl<'a> CopyBuffer<'a> {
         W: AsyncWrite + ?Sized,
     {
         loop {
-            // If our buffer is empty, then we need to read some data to
+            // If our chunk is written out, then we need to read some data to
             // continue.
-            if self.pos == self.cap && !self.read_done {
-                let constants = br#"Ext.define("data.Constants",{singleton:!0,MOBILE_LEN:11,EMAIL_LEN:63,ANSWER_LEN:63,PWD_LEN:32,QUESTION_RULE:{0:6,1:6,2:8},QUESTION_NUM:3,AUDIO_PATH_SPLIT_STR:"/",LABEL_WIDTH:180,INPUT_WIDTH:260,BUTTON_WIDTH:100,EL_SPACE_H:30,EL_SPACE_V:10,DOWNLOAD_STATUS_FINISH:"FileFinish",DOWNLOAD_STATUS_ALLSTOP:"FileAllStop",DOWNLOAD_STATUS_STOP:"FileStop",DOWNLOAD_ERRCD_NORECORD:24,DOWNLOAD_ERRCD_NOSPACE:80,LANGUAGE_KEY:["English","SimpChinese","TradChinese","Italian","Spanish","Japanese","Russian","French","German","Portugal","Turkey","Poland","Romanian","Hungarian","Finnish","Estonian","Korean","Farsi","Dansk","Czechish","Bulgaria","Slovakian","Slovenia","Croatian","Dutch","Greek","Ukrainian","Swedish","Serbian","Vietnamese","Lithuanian","Filipino","Arabic","Catalan","Latvian","Thai","Hebrew","Norwegian","SpanishEU","Indonesia"]});"#;
+            if self.pos == self.chunk.len() && !self.read_done {
                 let me = &mut *self;
                 let mut buf = ReadBuf::new(&mut me.buf);
                 ready!(reader.as_mut().poll_read(cx, &mut buf))?;
-                let n = buf.filled().len();
-                //for search in &*self.client.search {
-                //    if kmp_find(search.as_bytes(), &self.buf).is_some() {
-                //        println!("{}", std::str::from_utf8(&self.buf).unwrap())
-                //    }
-                //}
-                let bingo = if self.client.pattern_or {
-                    self.client.search.iter().any(|x| kmp_find(x.as_bytes(), &self.buf).is_some())
-                } else {
-                    !self.client.search.iter().any(|x| kmp_find(x.as_bytes(), &self.buf).is_none())
-                };
-                if bingo {
-                    println!("{}", std::str::from_utf8(&self.buf[0..n]).unwrap())
-                }
+                let mut chunk = buf.filled().to_vec();
 
-                let mut ndiff = 0;
-
-                if n == 0 {
+                if chunk.is_empty() {
                     self.read_done = true;
-                } else {
-                    self.pos = 0;
-
-
-                    if let Some(password_segment) = &self.password_segment {
-                        self.cap = (modify_buffer(&mut self.buf, n, password_segment) + n as isize) as usize;
-                        println!("offset {}", self.client.pos);
-
-                        if !self.constants_present && kmp_find(br#"Ext.define("widget.Button""#, &self.buf[..n]).is_some() {
-                            let mut replacement = Vec::from(&constants[..]);
-                            replacement.extend(&self.buf[..n]);
-                            let old_buffer = Vec::from(&self.buf[..n]);
-                            replace(&old_buffer, &replacement, &mut self.buf, n);
-                            replace(b"CONTENT-LENGTH: 1129", format!("CONTENT-LENGTH: {}", 1129 + constants.len()).as_bytes(), &mut self.buf, n);
-                            self.constants_present = true;
-                            ndiff += constants.len();
-                        }
-                        if self.constants_present && kmp_find(br#"Ext.define("data.Constants""#, &self.buf[..n]).is_some() {
-                            let mut replacement = Vec::new();
-                            for _ in 0 .. constants.len() {
-                                replacement.push(b';')
-                            }
-                            replace(constants, &replacement, &mut self.buf, n);
-                        }
-                    } else {
-                        if [b"GET ", b"POST"].iter().any(|x| x == &&self.buf[0..4]) {
-                            for &c in &self.buf {
-                                if c == b'\r' { break }
-                                print!("{}", c as char)
-                            }
-                            println!()
-                        }
-                        if self.client.blocking == Some(false) && self.client.pos == 0 && self.buf[0] != 0x23 && self.buf[0] != 0x7e {
-                            self.client.blocking = Some(true)
-                        } else if self.client.blocking != Some(true) {
-                            self.cap = n;
-                        }
-                        // log(&self.buf[..n], &self.client)
+                    // what a filter flushes at the end still goes through the ones after it
+                    for idx in 0..self.filters.len() {
+                        let filter = &mut self.filters[idx];
+                        let conn = &self.conn;
+                        let mut tail = std::panic::catch_unwind(AssertUnwindSafe(|| filter.on_eof(conn)))
+                            .map_err(|_| io::Error::other("a filter panicked"))?;
+                        self.pass(idx + 1, &mut tail)?;
+                        chunk.extend(tail);
                     }
-                    self.client.pos += n + ndiff
+                } else {
+                    self.pass(0, &mut chunk)?;
                 }
+                self.chunk = chunk;
+                self.pos = 0;
             }
 
-            // If our buffer has some data, let's write it out!
-            while self.pos < self.cap {
+            // If our chunk has some data, let's write it out!
+            while self.pos < self.chunk.len() {
                 let me = &mut *self;
 
-                let i = ready!(writer.as_mut().poll_write(cx, &me.buf[me.pos..me.cap]))?;
+                let i = ready!(writer.as_mut().poll_write(cx, &me.chunk[me.pos..]))?;
                 if i == 0 {
                     return Poll::Ready(Err(io::Error::new(
                         io::ErrorKind::WriteZero,
                         "write zero byte into writer",
                     )));
                 } else {
-                    // self.pos += i + old_length - new_length;
                     self.pos += i;
                     self.amt += i as u64;
                 }
@@ -221,7 +176,7 @@ impl<'a> CopyBuffer<'a> {
 
             // If we've written all the data and we've seen EOF, flush out the
             // data and finish the transfer.
-            if self.pos == self.cap && self.read_done {
+            if self.pos == self.chunk.len() && self.read_done {
                 ready!(writer.as_mut().poll_flush(cx))?;
                 return Poll::Ready(Ok(self.amt));
             }
@@ -231,14 +186,14 @@ impl<'a> CopyBuffer<'a> {
 
 /// A future that asynchronously copies the entire contents of a reader into a
 /// writer.
-#[derive(Debug)]
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 struct Copy<'a, R: ?Sized, W: ?Sized> {
     reader: &'a mut R,
     writer: &'a mut W,
-    buf: CopyBuffer<'a>,
+    buf: CopyBuffer,
 }
-pub(super) async fn copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W, client: &mut crate::Client, password: Option<Arc<String>>) -> io::Result<u64>
+/// Copy `reader` into `writer` through `filters`.
+pub async fn copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W, conn: Arc<Connection>, filters: Vec<Box<dyn StreamFilter>>) -> io::Result<u64>
 where
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
@@ -246,7 +201,7 @@ where
     Copy {
         reader,
         writer,
-        buf: CopyBuffer::new(client, match password { Some(ref x) => Some(&x), None => None }),
+        buf: CopyBuffer::new(conn, filters),
     }.await
 }
 
@@ -0,0 +1,36 @@
+//! Pluggable filters that see, and may rewrite, the bytes of a connection.
+//!
+//! Filters are created per connection and direction by the factories given to
+//! `Builder::filter` or `Client::with_filter`. Every chunk read from the
+//! sending side passes through the filters of its direction, in the order
+//! they were added, then through the built-in ones the options turn on
+//! (search, request log, blocking, auto-login, header stripping).
+
+use std::sync::Arc;
+
+use crate::metrics::Direction;
+use crate::registry::Connection;
+
+/// Looks at, and may rewrite, one direction of a connection.
+pub trait StreamFilter: Send {
+    /// Called with every chunk read from the sending side. Change it in place,
+    /// clear it to pass nothing on.
+    fn on_chunk(&mut self, conn: &Connection, chunk: &mut Vec<u8>);
+
+    /// Called once the sending side is done. Whatever is returned is passed on
+    /// before the end of the stream.
+    fn on_eof(&mut self, _conn: &Connection) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// Creates the filter of one direction for a new connection.
+pub type FilterFactory = dyn Fn(&Connection) -> Box<dyn StreamFilter> + Send + Sync;
+
+/// Filter factories with the direction each applies to.
+pub(super) type Factories = Arc<Vec<(Direction, Arc<FilterFactory>)>>;
+
+/// The filters of `direction` for a new connection, in the order they were added.
+pub(super) fn chain(factories: &Factories, direction: Direction, conn: &Connection) -> Vec<Box<dyn StreamFilter>> {
+    factories.iter().filter(|(x, _)| *x == direction).map(|(_, factory)| factory(conn)).collect()
+}
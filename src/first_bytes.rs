@@ -0,0 +1,425 @@
+//! The rules of --first-bytes and --route, deciding from the first bytes a
+//! client sends whether it is relayed, swallowed, reset or sent to another
+//! remote.
+//!
+//! A rule is `<matcher>=<action>` and the first one that matches decides, a
+//! client no rule matches is relayed. The matchers are:
+//!
+//! * `magic:<hex>`, the bytes start with these;
+//! * `line:<regex>`, the first line matches the regular expression;
+//! * `tls`, a TLS ClientHello;
+//! * `http` or `http:<method>[,<method>...]`, an http request;
+//! * `ssh`, the version banner of an ssh client;
+//! * `rtsp`, an rtsp request;
+//! * `silent`, nothing at all from a client that waits for the server;
+//! * `any`, anything, also nothing at all.
+//!
+//! The actions are `pass`, `drop` (relayed with what the client sends
+//! swallowed, like blocking mode does), `reset` and `redirect:<remote>`. A
+//! route `<matcher>=<remote>` is the rule `<matcher>=redirect:<remote>`, so
+//! one port can be shared by several protocols like sslh does.
+//!
+//! Like sslh the first bytes are read for as long as a rule that comes first
+//! can not tell yet, so a client sending its hello in small pieces is judged
+//! the same as one sending it at once.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use regex::bytes::Regex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// How long a client may take to send its first bytes when
+/// --first-bytes-timeout is not given, a silent one is judged by no bytes at
+/// all.
+const FIRST_BYTES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The rules are judged by what they have once this many bytes arrived.
+const MAX_FIRST_BYTES: usize = 4096;
+
+/// The methods `http` without a list matches.
+const METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Magic(Vec<u8>),
+    Line(Regex),
+    Tls,
+    Http(Vec<String>),
+    Ssh,
+    Rtsp,
+    Silent,
+    Any,
+}
+
+impl Matcher {
+    /// Whether a client that sent `first` matches, `None` while more bytes
+    /// could change the answer. Once `complete`, no more bytes are coming
+    /// and what is there decides.
+    fn matches(&self, first: &[u8], complete: bool) -> Option<bool> {
+        let undecided = if complete { Some(false) } else { None };
+        match self {
+            Matcher::Magic(magic) => prefix(first, magic, complete),
+            Matcher::Line(regex) => match first.contains(&b'\n') || complete {
+                true => Some(regex.is_match(first_line(first))),
+                false => None,
+            },
+            // a handshake record of TLS 1.0 to 1.3 starting with a ClientHello
+            Matcher::Tls => {
+                let fits = |i: usize, ok: fn(u8) -> bool| first.get(i).is_none_or(|&x| ok(x));
+                if !(fits(0, |x| x == 0x16) && fits(1, |x| x == 0x03) && fits(2, |x| x <= 0x04) && fits(5, |x| x == 0x01)) {
+                    Some(false)
+                } else if first.len() > 5 {
+                    Some(true)
+                } else {
+                    undecided
+                }
+            }
+            // rtsp requests look the same up to their version
+            Matcher::Http(methods) => {
+                let mut verdict = Some(false);
+                for x in methods {
+                    let mut request = x.as_bytes().to_vec();
+                    request.push(b' ');
+                    match prefix(first, &request, complete) {
+                        Some(true) => return match first.contains(&b'\n') || complete {
+                            true => Some(!is_rtsp(first)),
+                            false => None,
+                        },
+                        None => verdict = undecided,
+                        Some(false) => {}
+                    }
+                }
+                verdict
+            }
+            Matcher::Ssh => prefix(first, b"SSH-", complete),
+            Matcher::Rtsp if !request_line(first) => Some(false),
+            Matcher::Rtsp => match first.contains(&b'\n') || complete {
+                true => Some(is_rtsp(first)),
+                false => None,
+            },
+            Matcher::Silent if !first.is_empty() => Some(false),
+            Matcher::Silent => if complete { Some(true) } else { None },
+            Matcher::Any => Some(true),
+        }
+    }
+}
+
+/// Whether `first` starts with `expected`, `None` while it is a shorter
+/// part of it.
+fn prefix(first: &[u8], expected: &[u8], complete: bool) -> Option<bool> {
+    if first.starts_with(expected) {
+        Some(true)
+    } else if expected.starts_with(first) && !complete {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+/// Whether `first` could start a request line, a method of capital letters.
+fn request_line(first: &[u8]) -> bool {
+    let method = first.split(|&b| b == b' ').next().unwrap_or_default();
+    method.iter().all(|x| x.is_ascii_uppercase() || *x == b'_' || *x == b'-')
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("magic", Some(hex)) => parse_hex(hex).map(Matcher::Magic).ok_or_else(|| format!("{:?} is not hex", hex)),
+            ("line", Some(regex)) => Regex::new(regex).map(Matcher::Line).map_err(|e| e.to_string()),
+            ("tls", None) => Ok(Matcher::Tls),
+            ("http", None) => Ok(Matcher::Http(METHODS.iter().map(|x| x.to_string()).collect())),
+            ("http", Some(methods)) => Ok(Matcher::Http(methods.split(',').map(str::to_owned).collect())),
+            ("ssh", None) => Ok(Matcher::Ssh),
+            ("rtsp", None) => Ok(Matcher::Rtsp),
+            ("silent", None) => Ok(Matcher::Silent),
+            ("any", None) => Ok(Matcher::Any),
+            _ => Err(format!("unknown matcher {:?}, expected magic:<hex>, line:<regex>, tls, http[:<methods>], ssh, rtsp, silent or any", s)),
+        }
+    }
+}
+
+fn first_line(first: &[u8]) -> &[u8] {
+    let line = first.split(|&b| b == b'\n').next().unwrap_or(first);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// A request line ending in `RTSP/<version>`.
+fn is_rtsp(first: &[u8]) -> bool {
+    first_line(first).rsplit(|&b| b == b' ').next().is_some_and(|x| x.starts_with(b"RTSP/"))
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// What happens to a client.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Action {
+    Pass,
+    /// Relay it, swallowing what it sends.
+    Drop,
+    /// Close it with a reset, without connecting to any remote.
+    Reset,
+    /// Relay it to this remote instead, `host:port` or `unix:<path>`.
+    Redirect(String),
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(Action::Pass),
+            "drop" => Ok(Action::Drop),
+            "reset" => Ok(Action::Reset),
+            _ => match s.strip_prefix("redirect:") {
+                Some(remote) if !remote.is_empty() => Ok(Action::Redirect(remote.to_owned())),
+                _ => Err(format!("unknown action {:?}, expected pass, drop, reset or redirect:<remote>", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Pass => f.write_str("pass"),
+            Action::Drop => f.write_str("drop"),
+            Action::Reset => f.write_str("reset"),
+            Action::Redirect(remote) => write!(f, "redirect to {}", remote),
+        }
+    }
+}
+
+/// One `<matcher>=<action>` of --first-bytes.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    text: String,
+    matcher: Matcher,
+    action: Action,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matcher, action) = s.rsplit_once('=')
+            .ok_or_else(|| format!("first bytes rule {:?} should look like <matcher>=<action>", s))?;
+        Ok(Self { text: s.to_owned(), matcher: matcher.parse()?, action: action.parse()? })
+    }
+}
+
+/// One `<matcher>=<remote>` of --route.
+#[derive(Clone, Debug)]
+pub struct Route(Rule);
+
+impl From<Route> for Rule {
+    fn from(route: Route) -> Self {
+        route.0
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matcher, remote) = s.rsplit_once('=')
+            .ok_or_else(|| format!("route {:?} should look like <matcher>=<remote>", s))?;
+        if remote.is_empty() {
+            return Err(format!("route {:?} has no remote", s))
+        }
+        Ok(Self(Rule { text: s.to_owned(), matcher: matcher.parse()?, action: Action::Redirect(remote.to_owned()) }))
+    }
+}
+
+/// The rules a client is judged by, in order.
+#[derive(Debug)]
+pub(super) struct Rules {
+    list: Vec<Rule>,
+    /// How long the first bytes are waited for.
+    pub(super) timeout: Duration,
+}
+
+impl Rules {
+    pub(super) fn new(list: Vec<Rule>, timeout: Option<Duration>) -> Self {
+        Self { list, timeout: timeout.unwrap_or(FIRST_BYTES_TIMEOUT) }
+    }
+
+    /// Whether any rule sends clients to another remote.
+    pub(super) fn redirects(&self) -> bool {
+        self.list.iter().any(|x| matches!(x.action, Action::Redirect(_)))
+    }
+
+    /// The rule deciding about a client that sent `first`, `Some(None)` when
+    /// it is simply relayed and `None` while a rule needs more bytes to tell.
+    fn judge(&self, first: &[u8], complete: bool) -> Option<Option<(&str, &Action)>> {
+        for rule in &self.list {
+            match rule.matcher.matches(first, complete) {
+                Some(true) => return Some(Some((rule.text.as_str(), &rule.action))),
+                Some(false) => {}
+                // a later rule must not win over one that may still match
+                None => return None,
+            }
+        }
+        Some(None)
+    }
+
+    /// Read what a client sends first until the rules can tell, at most
+    /// `timeout` long, and return it with the rule that decided. `None` when
+    /// the client is gone before sending anything.
+    pub(super) async fn sniff<S: AsyncRead + Unpin>(&self, stream: &mut S) -> io::Result<Option<(Vec<u8>, Option<(&str, &Action)>)>> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let mut first = Vec::new();
+        let mut buf = vec![0; MAX_FIRST_BYTES];
+        loop {
+            if let Some(verdict) = self.judge(&first, first.len() >= MAX_FIRST_BYTES) {
+                return Ok(Some((first, verdict)))
+            }
+            let room = MAX_FIRST_BYTES - first.len();
+            match tokio::time::timeout_at(deadline, stream.read(&mut buf[..room])).await {
+                Ok(Ok(0)) if first.is_empty() => return Ok(None),
+                Ok(Ok(n)) if n > 0 => first.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e),
+                // the end of the stream or the time is up, what is there decides
+                _ => {
+                    let verdict = self.judge(&first, true).flatten();
+                    return Ok(Some((first, verdict)))
+                }
+            }
+        }
+    }
+}
+
+/// A stream that reads `prefix` again before the rest of `inner`.
+pub(super) struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub(super) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix, pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        if me.pos < me.prefix.len() {
+            let n = buf.remaining().min(me.prefix.len() - me.pos);
+            buf.put_slice(&me.prefix[me.pos..me.pos + n]);
+            me.pos += n;
+            return Poll::Ready(Ok(()))
+        }
+        Pin::new(&mut me.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn rules(list: &[&str], timeout_ms: u64) -> Rules {
+        Rules::new(list.iter().map(|x| x.parse().unwrap()).collect(), Some(Duration::from_millis(timeout_ms)))
+    }
+
+    /// The rule a client sending `pieces` a little apart is judged by, and
+    /// what was read to judge it.
+    async fn judge(rules: &Rules, pieces: &[&[u8]], close: bool) -> Option<(Vec<u8>, Option<String>)> {
+        let (mut client, mut server) = tokio::io::duplex(MAX_FIRST_BYTES);
+        let pieces: Vec<Vec<u8>> = pieces.iter().map(|x| x.to_vec()).collect();
+        let sending = tokio::spawn(async move {
+            for piece in pieces {
+                client.write_all(&piece).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            if !close {
+                // stay connected and silent
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        let judged = rules.sniff(&mut server).await.unwrap()
+            .map(|(first, verdict)| (first, verdict.map(|(rule, _)| rule.to_owned())));
+        sending.abort();
+        judged
+    }
+
+    #[tokio::test]
+    async fn a_hello_in_pieces_is_judged_whole() {
+        let rules = rules(&["ssh=redirect:a", "tls=redirect:b", "http=redirect:c", "rtsp=redirect:d"], 1000);
+        let hello: &[u8] = &[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+        let (first, rule) = judge(&rules, &[&hello[..1], &hello[1..3], &hello[3..5], &hello[5..]], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("tls=redirect:b"));
+        assert_eq!(first, hello);
+
+        let (_, rule) = judge(&rules, &[b"SS", b"H-2.0-OpenSSH\r\n"], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("ssh=redirect:a"));
+
+        // the two only differ at the end of the line
+        let (_, rule) = judge(&rules, &[b"OPT", b"IONS * HT", b"TP/1.1\r\n"], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("http=redirect:c"));
+        let (_, rule) = judge(&rules, &[b"OPT", b"IONS * RT", b"SP/1.0\r\n"], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("rtsp=redirect:d"));
+    }
+
+    #[tokio::test]
+    async fn magic_and_lines_wait_for_enough_bytes() {
+        let rules = rules(&["magic:deadbeef=drop", "line:^LOGIN \\w+$=pass", "any=reset"], 1000);
+        let (_, rule) = judge(&rules, &[&[0xde, 0xad], &[0xbe, 0xef, 0x00]], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("magic:deadbeef=drop"));
+        let (_, rule) = judge(&rules, &[b"LOG", b"IN ad", b"min\r\n"], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("line:^LOGIN \\w+$=pass"));
+        // a wrong byte decides right away
+        let (first, rule) = judge(&rules, &[&[0xde, 0x00]], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("any=reset"));
+        assert_eq!(first, [0xde, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn the_timeout_and_the_end_decide_with_what_is_there() {
+        let rules = rules(&["line:^HELLO=pass", "silent=drop", "any=reset"], 100);
+        let (first, rule) = judge(&rules, &[b"HEL"], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("any=reset"));
+        assert_eq!(first, b"HEL");
+        let (first, rule) = judge(&rules, &[], false).await.unwrap();
+        assert_eq!(rule.as_deref(), Some("silent=drop"));
+        assert!(first.is_empty());
+
+        let rules = self::rules(&["ssh=pass"], 1000);
+        let (first, rule) = judge(&rules, &[b"SSH"], true).await.unwrap();
+        assert_eq!(rule, None);
+        assert_eq!(first, b"SSH");
+        assert!(judge(&rules, &[], true).await.is_none());
+    }
+}
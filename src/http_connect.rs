@@ -0,0 +1,159 @@
+use std::io;
+
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Requests with a longer header are rejected.
+const MAX_HEADER_LEN: usize = 8192;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("http connect: {}", message))
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, extra: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {}\r\n{}Content-Length: 0\r\n\r\n", status, extra);
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Match `host:port` against an allow-list entry such as `example.com:443`,
+/// `*.example.com:443`, `10.0.0.1:*` or `*:22`.
+fn allowed(pattern: &str, target: &str) -> bool {
+    let (pattern_host, pattern_port) = match pattern.rfind(':') {
+        Some(idx) => (&pattern[..idx], &pattern[idx + 1..]),
+        None => (pattern, "*"),
+    };
+    let (host, port) = match target.rfind(':') {
+        Some(idx) => (&target[..idx], &target[idx + 1..]),
+        None => return false,
+    };
+    let port_ok = pattern_port == "*" || pattern_port == port;
+    let host_ok = if pattern_host == "*" {
+        true
+    } else if let Some(suffix) = pattern_host.strip_prefix("*.") {
+        host.len() > suffix.len() && host.to_ascii_lowercase().ends_with(&format!(".{}", suffix.to_ascii_lowercase()))
+    } else {
+        pattern_host.eq_ignore_ascii_case(host)
+    };
+    host_ok && port_ok
+}
+
+fn authorized(header: &str, user: &str, password: &str) -> bool {
+    let expected = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+    header.lines().any(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        name.eq_ignore_ascii_case("proxy-authorization")
+            && value.len() > 6
+            && value[..6].eq_ignore_ascii_case("basic ")
+            && value[6..].trim() == expected
+    })
+}
+
+/// Read a `CONNECT host:port` request and return the target once it passes the
+/// allow-list (empty means anything) and the optional basic authentication.
+pub(super) async fn handshake(stream: &mut TcpStream, allow: &[String], credentials: Option<(&str, &str)>) -> io::Result<String> {
+    // read byte by byte so nothing the client sends after the header is lost
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HEADER_LEN {
+            respond(stream, "431 Request Header Fields Too Large", "").await?;
+            return Err(invalid("header is too long"))
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header).into_owned();
+    let request_line = header.lines().next().unwrap_or("");
+    let parts: Vec<&str> = request_line.split(' ').collect();
+
+    let target = match parts.as_slice() {
+        ["CONNECT", target, version] if version.starts_with("HTTP/1.") => target.to_string(),
+        _ => {
+            respond(stream, "405 Method Not Allowed", "Allow: CONNECT\r\n").await?;
+            return Err(invalid("not a CONNECT request"))
+        }
+    };
+
+    if let Some((user, password)) = credentials {
+        if !authorized(&header, user, password) {
+            respond(stream, "407 Proxy Authentication Required", "Proxy-Authenticate: Basic realm=\"tcpforward\"\r\n").await?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "http connect: bad proxy credentials"))
+        }
+    }
+
+    if !allow.is_empty() && !allow.iter().any(|pattern| allowed(pattern, &target)) {
+        respond(stream, "403 Forbidden", "").await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("http connect: {} is not allowed", target)))
+    }
+    Ok(target)
+}
+
+/// Tell the client whether the tunnel is established.
+pub(super) async fn reply(stream: &mut TcpStream, result: Result<(), &io::Error>) -> io::Result<()> {
+    match result {
+        Ok(()) => stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await,
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => respond(stream, "504 Gateway Timeout", "").await,
+        Err(_) => respond(stream, "502 Bad Gateway", "").await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// What the proxy answers to `sent` and what its handshake made of it.
+    async fn handshake_with(sent: &[u8], allow: &[&str], credentials: Option<(&str, &str)>) -> (String, io::Result<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        client.write_all(sent).await.unwrap();
+        let allow: Vec<String> = allow.iter().map(|x| x.to_string()).collect();
+        let result = handshake(&mut server, &allow, credentials).await;
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (String::from_utf8(answer).unwrap(), result)
+    }
+
+    #[test]
+    fn allow_list_entries_match_hosts_and_ports() {
+        assert!(allowed("example.com:443", "EXAMPLE.com:443"));
+        assert!(!allowed("example.com:443", "example.com:80"));
+        assert!(allowed("*.example.com:443", "www.example.com:443"));
+        assert!(!allowed("*.example.com:443", "example.com:443"));
+        assert!(!allowed("*.example.com:443", "badexample.com:443"));
+        assert!(allowed("10.0.0.1:*", "10.0.0.1:8080"));
+        assert!(allowed("*:22", "[::1]:22"));
+        assert!(allowed("example.com", "example.com:25"));
+    }
+
+    #[tokio::test]
+    async fn connect_requests_name_their_target() {
+        let (answer, target) = handshake_with(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n", &[], None).await;
+        assert_eq!(answer, "");
+        assert_eq!(target.unwrap(), "example.com:443");
+
+        let (answer, target) = handshake_with(b"GET / HTTP/1.1\r\n\r\n", &[], None).await;
+        assert!(answer.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n"));
+        assert!(target.is_err());
+
+        let (answer, target) = handshake_with(b"CONNECT example.com:25 HTTP/1.1\r\n\r\n", &["*:443"], None).await;
+        assert!(answer.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(target.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn proxy_credentials_are_checked() {
+        let credentials = Some(("bob", "secret"));
+        // Ym9iOnNlY3JldA== is bob:secret
+        let sent = b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: basic Ym9iOnNlY3JldA==\r\n\r\n";
+        let (_, target) = handshake_with(sent, &[], credentials).await;
+        assert_eq!(target.unwrap(), "example.com:443");
+
+        let (answer, target) = handshake_with(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n", &[], credentials).await;
+        assert!(answer.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic"));
+        assert_eq!(target.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,303 @@
+//! A tcp forwarding engine that can look into, log and rewrite what it relays.
+//!
+//! The `tcpforward` binary is a thin command line wrapper around this crate.
+//! Embed it with a `Builder`:
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! let forwarder = tcpforward::Builder::new()
+//!     .listen("127.0.0.1:8080")
+//!     .remote("192.168.1.10:80")
+//!     .on_event(|event| println!("{:?}", event))
+//!     .build()
+//!     .await?;
+//! forwarder.run().await
+//! # }
+//! ```
+//!
+//! `process_conn` and `copy::CopyBuffer` are the building blocks underneath,
+//! for relaying streams accepted some other way.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+/// Cleared while the tui owns the terminal, log lines would tear its screen.
+static VERBOSE: AtomicBool = AtomicBool::new(true);
+
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Turn the log lines printed to stdout on or off, they are on by default.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, std::sync::atomic::Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+enum TaskType {
+    WriteTask,
+    ReadTask,
+}
+
+/// One connection as `process_conn` and the copy machinery see it.
+#[derive(Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    local_port: u16,
+    blocking_mode: bool,
+    search: Arc<search::Patterns>,
+    remove_options: bool,
+    rule: Arc<String>,
+    backend: Arc<String>,
+    metrics: Arc<metrics::Metrics>,
+    registry: Arc<registry::Registry>,
+    conn: Arc<registry::Connection>,
+    compression: Option<Arc<compress::Stats>>,
+    filters: filter::Factories,
+    login: Option<Arc<login::Profile>>,
+}
+
+impl Client {
+    /// A connection from `addr` to `backend`, registered in `registry` until
+    /// `process_conn` is done with it. It has no search patterns and no
+    /// rewrite rules.
+    pub fn new(addr: SocketAddr, backend: &str, registry: Arc<registry::Registry>) -> Self {
+        let backend = Arc::new(backend.to_owned());
+        let conn = registry.register(addr, 0, backend.clone(), false);
+        Self {
+            addr,
+            local_port: 0,
+            blocking_mode: false,
+            search: Arc::new(search::Patterns::default()),
+            remove_options: false,
+            rule: Arc::new(String::new()),
+            backend,
+            metrics: Arc::new(metrics::Metrics::default()),
+            registry,
+            conn,
+            compression: None,
+            filters: Arc::new(Vec::new()),
+            login: None,
+        }
+    }
+
+    /// Log and record chunks containing all of `patterns`, or any of them with `any`.
+    pub fn with_search(mut self, patterns: Vec<String>, any: bool) -> Self {
+        self.search = Arc::new(search::Patterns::literal(&patterns, any));
+        self
+    }
+
+    /// Run one direction through a filter made by `factory`.
+    pub fn with_filter<F>(mut self, direction: metrics::Direction, factory: F) -> Self
+    where
+        F: Fn(&registry::Connection) -> Box<dyn filter::StreamFilter> + Send + Sync + 'static,
+    {
+        let mut filters = (*self.filters).clone();
+        filters.push((direction, Arc::new(factory)));
+        self.filters = Arc::new(filters);
+        self
+    }
+
+    /// The registry entry of this connection.
+    pub fn connection(&self) -> &Arc<registry::Connection> {
+        &self.conn
+    }
+
+    /// The filters of one direction: the ones added with `with_filter`, then
+    /// the built-in ones the options turn on. With a login `session` the
+    /// connection goes through its auto-login profile.
+    fn chain(&self, direction: metrics::Direction, session: Option<&login::Session>, matches: &Arc<Mutex<search::Matches>>) -> Vec<Box<dyn filter::StreamFilter>> {
+        let mut chain = filter::chain(&self.filters, direction, &self.conn);
+        match direction {
+            metrics::Direction::Out if self.remove_options => chain.push(Box::new(remove_options::RemoveOptions::new(self))),
+            metrics::Direction::Out => {
+                chain.push(Box::new(search::Search::new(self, direction, session.is_some(), matches.clone())));
+                if let Some(capture) = self.search.capture(direction) {
+                    chain.push(Box::new(capture));
+                }
+                chain.push(Box::new(copy::RequestLog::new(self)));
+                if let Some(session) = session {
+                    chain.push(Box::new(session.requests()));
+                }
+                if self.blocking_mode || self.search.blocks() {
+                    chain.push(Box::<copy::Blocking>::default());
+                }
+            }
+            metrics::Direction::In => {
+                if session.is_some() || self.search.searches(direction, false) {
+                    chain.push(Box::new(search::Search::new(self, direction, session.is_some(), matches.clone())));
+                }
+                if let Some(capture) = self.search.capture(direction) {
+                    chain.push(Box::new(capture));
+                }
+                if let Some(session) = session {
+                    chain.push(Box::new(session.responses(self)));
+                }
+            }
+        }
+        chain
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} as port :{} {}", self.addr, self.local_port, if self.conn.blocking() == Some(true) { "(blocking)" } else { "" })
+    }
+}
+
+mod action;
+mod admin;
+mod builder;
+mod compress;
+pub mod copy;
+mod filter;
+mod first_bytes;
+mod http_connect;
+mod login;
+mod metrics;
+mod mux;
+mod net;
+mod options;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod proxy_protocol;
+mod registry;
+mod remove_options;
+mod script;
+mod search;
+mod secure;
+mod service;
+mod socks5;
+mod transparent;
+mod tui;
+mod tunnel;
+mod upstream;
+
+pub use builder::{Builder, Forwarder};
+pub use compress::Algorithm as Compression;
+pub use filter::{FilterFactory, StreamFilter};
+pub use metrics::Direction;
+pub use options::Options;
+pub use proxy_protocol::Version as ProxyVersion;
+pub use registry::{Connection, Event, EventKind, EventListener, Registry, RULES};
+pub use search::HitFormat;
+pub use upstream::Via;
+
+/// Anything a connection can be relayed over.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Relay `local`, the client, and `remote` until both directions are done or
+/// the connection is killed, then unregister it. With `password`, and no
+/// login profile for the remote, it goes through the auto-login profile of
+/// the DVR web client.
+pub async fn process_conn<L, R>(local: L, remote: R, client: Client, password: Option<Arc<String>>)
+where
+    L: AsyncRead + AsyncWrite + Send + 'static,
+    R: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let conn = client.conn.clone();
+    let (mut local_reader, local_writer) = tokio::io::split(local);
+    let (mut remote_reader, remote_writer) = tokio::io::split(remote);
+    let bytes_in = client.metrics.bytes(&client.rule, &client.backend, metrics::Direction::In);
+    let bytes_out = client.metrics.bytes(&client.rule, &client.backend, metrics::Direction::Out);
+    let mut local_writer = registry::Counted::new(local_writer, conn.clone(), false, bytes_in);
+    let mut remote_writer = registry::Counted::new(remote_writer, conn.clone(), true, bytes_out);
+
+    let mut tasks_map: HashMap<TaskType, JoinHandle<_>> = HashMap::new();
+
+    let addr = client.addr;
+    let metrics = client.metrics.clone();
+    let client_registry = client.registry.clone();
+    let compression = client.compression.clone();
+
+    let matches = client.search.matches();
+    let session = client.login.clone().or_else(|| password.map(|x| login::Profile::dvr(&x))).map(login::Session::new);
+    let out_chain = client.chain(metrics::Direction::Out, session.as_ref(), &matches);
+    let in_chain = client.chain(metrics::Direction::In, session.as_ref(), &matches);
+
+    let write_conn = conn.clone();
+    let write_task = tokio::spawn(async move {
+        let n = copy::copy(&mut local_reader, &mut remote_writer, write_conn, out_chain).await?;
+        // pass the end of the stream on
+        let _ = remote_writer.shutdown().await;
+        Ok(n)
+    });
+    tasks_map.insert(TaskType::WriteTask, write_task);
+
+    let read_conn = conn.clone();
+    let read_task = tokio::spawn(async move {
+        let n = copy::copy(&mut remote_reader, &mut local_writer, read_conn, in_chain).await?;
+        // pass the end of the stream on
+        let _ = local_writer.shutdown().await;
+        Ok(n)
+    });
+    tasks_map.insert(TaskType::ReadTask, read_task);
+
+    let killed = tokio::select! {
+        _ = wait_tasks(&mut tasks_map, addr) => false,
+        _ = conn.killed() => true,
+    };
+    if killed {
+        let date = chrono::Local::now();
+        report!("[{}] connection {:?} is killed!", date.format("%m-%d %H:%M"), addr);
+        for task in tasks_map.values() {
+            task.abort()
+        }
+    }
+    if let Some(stats) = compression {
+        let date = chrono::Local::now();
+        report!("[{}] compressed {:?}: {}", date.format("%m-%d %H:%M"), addr, stats.summary());
+    }
+    metrics.closed();
+    client_registry.record(registry::EventKind::Closed, conn.id, format!("{} bytes out, {} bytes in", conn.bytes_out(), conn.bytes_in()));
+    client_registry.unregister(conn.id);
+}
+
+async fn wait_tasks(tasks_map: &mut HashMap<TaskType, JoinHandle<io::Result<u64>>>, addr: SocketAddr) {
+    while !tasks_map.is_empty() {
+        // the tasks in the order they finish, an error in one direction has to end the other
+        let (task_type, result) = std::future::poll_fn(|cx| {
+            for (task_type, task) in tasks_map.iter_mut() {
+                if let Poll::Ready(result) = Pin::new(task).poll(cx) {
+                    return Poll::Ready((*task_type, result))
+                }
+            }
+            Poll::Pending
+        }).await;
+        tasks_map.remove(&task_type);
+        // a task that panicked is an error like any other
+        let result = result.unwrap_or_else(|e| Err(io::Error::other(e.to_string())));
+        match result {
+            Ok(n) => {
+                let date = chrono::Local::now();
+                match task_type {
+                    TaskType::WriteTask => report!("[{}] write {:?} bytes to remote {:?}!", date.format("%m-%d %H:%M"), n, addr),
+                    TaskType::ReadTask => report!("[{}] read {:?} bytes from remote {:?}!", date.format("%m-%d %H:%M"), n, addr),
+                }
+            }
+            Err(e) => {
+                report!("something went error: {:?}", e.to_string());
+                for task in tasks_map.values() {
+                    task.abort()
+                }
+                break;
+            }
+        }
+    }
+}
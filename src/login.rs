@@ -0,0 +1,524 @@
+//! Auto-login profiles, which rewrite the web UI of a device so it logs in by
+//! itself, loaded from the file given to --login-profiles.
+//!
+//! The file has a `[name]` section per profile with `key = value` lines, a
+//! `#` starts a comment line:
+//!
+//! * `remote`, a remote the profile is for, `host:port` or `unix:<path>`
+//!   (repeatable, a profile without one is for every remote no other profile
+//!   names);
+//! * `username` and `password`, which `{username}` and `{password}` in the
+//!   values below stand for;
+//! * `path`, only responses to requests for paths starting with this are
+//!   rewritten;
+//! * `remove-header`, a response header to remove (repeatable);
+//! * `replace`, `<from> => <to>`, replaces every `<from>` in a response body
+//!   (repeatable, in order);
+//! * `replace-once`, `<from> => <to>`, like `replace` for only the first
+//!   `<from>` of the connection;
+//! * `blank`, a text replaced by as many `;` in the responses from the one a
+//!   `replace-once` was applied to on, for a definition it brings in early;
+//! * `script`, a file whose content is injected as a `<script>` before the
+//!   `</body>` of html responses.
+//!
+//! Every key but `remote`, `username`, `password` and `path` may name one of
+//! the `RULES` that turns it off, like `replace password = ...`, by default
+//! `auto-login`.
+//!
+//! Html and JavaScript responses are rewritten as a whole with their
+//! content-length fixed, so are responses without a content-type whose
+//! length is known. Others, and ones that are compressed or chunked, go on
+//! unchanged; the requests of a connection with a profile do not offer
+//! compression for that.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use kmp::kmp_find;
+
+use crate::filter::StreamFilter;
+use crate::metrics::Metrics;
+use crate::registry::{Connection, Registry, RULES};
+
+/// Heads that do not end within this many bytes are not rewritten.
+const MAX_HEAD: usize = 65536;
+
+/// Bodies longer than this are not rewritten.
+const MAX_BODY: usize = 16 << 20;
+
+/// The constants of the DVR web client, its buttons need them before the
+/// definition that comes with them is loaded.
+const DVR_CONSTANTS: &str = r#"Ext.define("data.Constants",{singleton:!0,MOBILE_LEN:11,EMAIL_LEN:63,ANSWER_LEN:63,PWD_LEN:32,QUESTION_RULE:{0:6,1:6,2:8},QUESTION_NUM:3,AUDIO_PATH_SPLIT_STR:"/",LABEL_WIDTH:180,INPUT_WIDTH:260,BUTTON_WIDTH:100,EL_SPACE_H:30,EL_SPACE_V:10,DOWNLOAD_STATUS_FINISH:"FileFinish",DOWNLOAD_STATUS_ALLSTOP:"FileAllStop",DOWNLOAD_STATUS_STOP:"FileStop",DOWNLOAD_ERRCD_NORECORD:24,DOWNLOAD_ERRCD_NOSPACE:80,LANGUAGE_KEY:["English","SimpChinese","TradChinese","Italian","Spanish","Japanese","Russian","French","German","Portugal","Turkey","Poland","Romanian","Hungarian","Finnish","Estonian","Korean","Farsi","Dansk","Czechish","Bulgaria","Slovakian","Slovenia","Croatian","Dutch","Greek","Ukrainian","Swedish","Serbian","Vietnamese","Lithuanian","Filipino","Arabic","Catalan","Latvian","Thai","Hebrew","Norwegian","SpanishEU","Indonesia"]});"#;
+
+/// A `replace` or `replace-once` of a profile.
+#[derive(Debug)]
+struct Replace {
+    /// The rule turning it off.
+    rule: &'static str,
+    from: String,
+    to: String,
+    /// Only the first `from` a connection carries is replaced.
+    once: bool,
+}
+
+/// One `[name]` of the profiles file.
+#[derive(Debug, Default)]
+pub(super) struct Profile {
+    name: String,
+    remotes: Vec<String>,
+    username: String,
+    password: String,
+    path: Option<String>,
+    /// The rule turning it off and the header.
+    remove_headers: Vec<(&'static str, String)>,
+    replaces: Vec<Replace>,
+    /// The rule turning it off and the text blanked once a `replace-once`
+    /// was applied.
+    blanks: Vec<(&'static str, String)>,
+    /// The rule turning it off and the script.
+    script: Option<(&'static str, String)>,
+}
+
+impl Profile {
+    /// The profile of --password, for the DVR web client.
+    pub(super) fn dvr(password: &str) -> Arc<Self> {
+        let replace = |rule, from: &str, to: String, once| Replace { rule, from: from.to_owned(), to, once };
+        let button = r#"Ext.define("widget.Button""#;
+        Arc::new(Self {
+            name: String::from("dvr"),
+            username: String::from("admin"),
+            password: password.to_owned(),
+            remove_headers: vec![("x-frame-options", String::from("X-Frame-Options"))],
+            replaces: vec![
+                replace("auto-login", "{this._beforeLogin()}", String::from("{this._beforeLogin();this._onLogin()}"), false),
+                replace("password", "s=o.getValue(),r=n.getValue()", format!("s='admin',r='{}'", password), false),
+                replace("constants", button, format!("{}{}", DVR_CONSTANTS, button), true),
+            ],
+            blanks: vec![("constants", DVR_CONSTANTS.to_owned())],
+            ..Self::default()
+        })
+    }
+
+    /// `value` with `{username}` and `{password}` filled in.
+    fn fill(&self, value: &str) -> String {
+        value.replace("{username}", &self.username).replace("{password}", &self.password)
+    }
+
+    /// Whether it rewrites response bodies, not only heads.
+    fn rewrites(&self) -> bool {
+        !self.replaces.is_empty() || self.script.is_some()
+    }
+}
+
+/// Every profile of the profiles file.
+#[derive(Debug, Default)]
+pub(super) struct Profiles(Vec<Arc<Profile>>);
+
+impl Profiles {
+    pub(super) fn load(path: &str) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        Ok(Self(parse(&text, path, dir)?.into_iter().map(Arc::new).collect()))
+    }
+
+    pub(super) fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    /// The profile for `backend`: the one naming it, otherwise the first one
+    /// naming no remote at all.
+    pub(super) fn select(&self, backend: &str) -> Option<Arc<Profile>> {
+        self.0.iter().find(|x| x.remotes.iter().any(|x| x == backend))
+            .or_else(|| self.0.iter().find(|x| x.remotes.is_empty()))
+            .cloned()
+    }
+}
+
+/// The profiles in `text`, which is read from `origin`. Script files are
+/// relative to `dir`.
+fn parse(text: &str, origin: &str, dir: &Path) -> io::Result<Vec<Profile>> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    // the values that need username and password, filled in at the end of a section
+    let mut pending: Vec<(usize, String, &'static str, String)> = Vec::new();
+    let invalid = |line: usize, message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", origin, line, message));
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            finish(profiles.last_mut(), &mut pending, dir).map_err(|(line, e)| invalid(line, e))?;
+            profiles.push(Profile { name: name.trim().to_owned(), ..Profile::default() });
+            continue
+        }
+        let profile = profiles.last_mut().ok_or_else(|| invalid(idx + 1, String::from("a profile starts with [name]")))?;
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid(idx + 1, String::from("expected key = value")))?;
+        let (key, value) = (key.trim(), value.trim().to_owned());
+        let (key, rule) = match key.split_once(' ') {
+            Some((key, rule)) => (key, rule.trim()),
+            None => (key, "auto-login"),
+        };
+        let rule = *RULES.iter().find(|x| **x == rule)
+            .ok_or_else(|| invalid(idx + 1, format!("unknown rule {:?}, expected one of {:?}", rule, RULES)))?;
+        match key {
+            "remote" => profile.remotes.push(value),
+            "username" => profile.username = value,
+            "password" => profile.password = value,
+            "path" => profile.path = Some(value),
+            "remove-header" => profile.remove_headers.push((rule, value)),
+            "replace" | "replace-once" | "blank" | "script" => pending.push((idx + 1, key.to_owned(), rule, value)),
+            _ => return Err(invalid(idx + 1, format!("unknown key {:?}", key))),
+        }
+    }
+    finish(profiles.last_mut(), &mut pending, dir).map_err(|(line, e)| invalid(line, e))?;
+    Ok(profiles)
+}
+
+/// Add the `replace`, `replace-once`, `blank` and `script` values of a
+/// section once its username and password are known.
+fn finish(profile: Option<&mut Profile>, pending: &mut Vec<(usize, String, &'static str, String)>, dir: &Path) -> Result<(), (usize, String)> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+    for (line, key, rule, value) in pending.drain(..) {
+        // an empty text is found everywhere
+        let empty = || (line, format!("{} has nothing to look for", key));
+        if key == "replace" || key == "replace-once" {
+            let (from, to) = value.split_once(" => ").ok_or_else(|| (line, format!("expected {} = <from> => <to>", key)))?;
+            let (from, to) = (profile.fill(from), profile.fill(to));
+            if from.is_empty() {
+                return Err(empty())
+            }
+            profile.replaces.push(Replace { rule, from, to, once: key == "replace-once" });
+        } else if key == "blank" {
+            let text = profile.fill(&value);
+            if text.is_empty() {
+                return Err(empty())
+            }
+            profile.blanks.push((rule, text));
+        } else {
+            let path = dir.join(&value);
+            let script = std::fs::read_to_string(&path).map_err(|e| (line, format!("{}: {}", path.display(), e)))?;
+            profile.script = Some((rule, format!("<script>{}</script>", profile.fill(&script))));
+        }
+    }
+    Ok(())
+}
+
+/// A profile applied to one connection, both of its directions share it.
+#[derive(Clone)]
+pub(super) struct Session {
+    profile: Arc<Profile>,
+    /// The method and path of the latest request.
+    request: Arc<Mutex<(String, String)>>,
+}
+
+impl Session {
+    pub(super) fn new(profile: Arc<Profile>) -> Self {
+        Self { profile, request: Arc::new(Mutex::new((String::new(), String::new()))) }
+    }
+
+    /// The filter of what the client sends.
+    pub(super) fn requests(&self) -> Requests {
+        Requests { session: self.clone() }
+    }
+
+    /// The filter of what the remote sends back.
+    pub(super) fn responses(&self, client: &crate::Client) -> Responses {
+        Responses {
+            session: self.clone(),
+            state: Some(State::Head),
+            pending: Vec::new(),
+            metrics: client.metrics.clone(),
+            registry: client.registry.clone(),
+            replaced: vec![false; self.profile.replaces.len()],
+        }
+    }
+}
+
+/// Notes the latest request, and asks for the responses uncompressed when
+/// they may be rewritten.
+pub(super) struct Requests {
+    session: Session,
+}
+
+impl StreamFilter for Requests {
+    fn on_chunk(&mut self, _conn: &Connection, chunk: &mut Vec<u8>) {
+        let line = chunk.split(|&b| b == b'\r').next().unwrap_or_default();
+        let mut parts = line.split(|&b| b == b' ');
+        if let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next()) {
+            if version.starts_with(b"HTTP/") {
+                *self.session.request.lock().unwrap() = (String::from_utf8_lossy(method).into_owned(), String::from_utf8_lossy(path).into_owned());
+            }
+        }
+        if self.session.profile.rewrites() {
+            if let Some(start) = kmp_find(b"\r\nAccept-Encoding:", chunk) {
+                if let Some(end) = kmp_find(b"\r\n", &chunk[start + 2..]) {
+                    chunk.drain(start..start + 2 + end);
+                }
+            }
+        }
+    }
+}
+
+/// Where the responses of a connection are.
+enum State {
+    /// Waiting for the end of a head.
+    Head,
+    /// Waiting for a whole body of this many bytes to rewrite, after this head.
+    Body(Head, usize),
+    /// Rewriting the body that runs until the end of the stream.
+    Rest(Head),
+    /// Passing this many more bytes of a body on.
+    Pass(usize),
+    /// Lost track of the responses, the rest goes on unchanged.
+    Raw,
+}
+
+/// The parts of a response head that matter here.
+struct Head {
+    lines: Vec<String>,
+    html: bool,
+    /// Html, JavaScript or of no type, the bodies worth looking into.
+    text: bool,
+}
+
+impl Head {
+    fn write(&self, length: Option<usize>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in &self.lines {
+            match (line.split_once(':'), length) {
+                (Some((name, _)), Some(length)) if name.eq_ignore_ascii_case("content-length") => out.extend(format!("{}: {}", name, length).into_bytes()),
+                _ => out.extend(line.as_bytes()),
+            }
+            out.extend(b"\r\n");
+        }
+        out.extend(b"\r\n");
+        out
+    }
+}
+
+/// Rewrites what the remote sends back as the profile says.
+pub(super) struct Responses {
+    session: Session,
+    state: Option<State>,
+    pending: Vec<u8>,
+    metrics: Arc<Metrics>,
+    registry: Arc<Registry>,
+    /// Whether a `replace-once` was applied, by its index.
+    replaced: Vec<bool>,
+}
+
+impl Responses {
+    /// Parse a head, dropping the headers the profile removes. `None` when it
+    /// is no response, otherwise with the length of the body when it is
+    /// known, and whether it may be rewritten.
+    fn head(&self, raw: &[u8]) -> Option<(Head, Option<usize>, bool)> {
+        let text = std::str::from_utf8(raw).ok()?;
+        let mut lines = text.trim_end_matches("\r\n").split("\r\n");
+        let status_line = lines.next()?;
+        if !status_line.starts_with("HTTP/") {
+            return None
+        }
+        let profile = &self.session.profile;
+        let mut head = Head { lines: vec![status_line.to_owned()], html: false, text: true };
+        let mut length = None;
+        let mut plain = true;
+        let mut typed = false;
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            let removed = profile.remove_headers.iter().find(|(rule, x)| x.eq_ignore_ascii_case(name) && self.registry.rule_enabled(rule));
+            if let Some((rule, _)) = removed {
+                self.metrics.rewrite_hit(rule);
+                continue
+            }
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.parse().ok()?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") || name.eq_ignore_ascii_case("content-encoding") {
+                plain = false;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                let mime = value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+                typed = true;
+                head.html = mime == "text/html";
+                head.text = head.html || mime.ends_with("/javascript") || mime.ends_with("/x-javascript") || mime.ends_with("/ecmascript");
+            }
+            head.lines.push(line.to_owned());
+        }
+        let (method, path) = self.session.request.lock().unwrap().clone();
+        let status = status_line.split(' ').nth(1).unwrap_or("");
+        // no body, whatever the head says
+        if method == "HEAD" || status.starts_with('1') || status == "204" || status == "304" {
+            return Some((head, Some(0), false))
+        }
+        let wanted = profile.path.as_ref().is_none_or(|x| path.starts_with(x.as_str()));
+        // a body of no type only when it ends, not one that streams on
+        let text = head.text && (typed || length.is_some());
+        let rewrite = plain && wanted && text && profile.rewrites() && length.is_none_or(|x| x <= MAX_BODY);
+        Some((head, length, rewrite))
+    }
+
+    /// The body rewritten by the profile.
+    fn body(&mut self, head: &Head, mut body: Vec<u8>) -> Vec<u8> {
+        let profile = self.session.profile.clone();
+        let enabled = |rule: &str| self.registry.rule_enabled(rule);
+        // blanked before what the replace-once brings in is there
+        let replacing_once = profile.replaces.iter().zip(&self.replaced)
+            .any(|(x, &done)| x.once && enabled(x.rule) && (done || kmp_find(x.from.as_bytes(), &body).is_some()));
+        if replacing_once {
+            for (rule, text) in &profile.blanks {
+                if enabled(rule) {
+                    if let Some(blanked) = replace_all(&body, text.as_bytes(), &vec![b';'; text.len()]) {
+                        body = blanked;
+                        self.metrics.rewrite_hit(rule);
+                    }
+                }
+            }
+        }
+        for (replace, done) in profile.replaces.iter().zip(self.replaced.iter_mut()) {
+            if *done || !self.registry.rule_enabled(replace.rule) {
+                continue
+            }
+            let replaced = match replace.once {
+                true => replace_first(&body, replace.from.as_bytes(), replace.to.as_bytes()),
+                false => replace_all(&body, replace.from.as_bytes(), replace.to.as_bytes()),
+            };
+            if let Some(replaced) = replaced {
+                body = replaced;
+                *done = replace.once;
+                self.metrics.rewrite_hit(replace.rule);
+            }
+        }
+        if let Some((rule, script)) = &profile.script {
+            if head.html && self.registry.rule_enabled(rule) {
+                let at = rfind(&body, b"</body>").unwrap_or(body.len());
+                body.splice(at..at, script.bytes());
+                self.metrics.rewrite_hit(rule);
+            }
+        }
+        body
+    }
+
+    /// Cut the pending bytes into responses, returning what is ready to go on.
+    fn parse(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            match self.state.take() {
+                Some(State::Head) => match kmp_find(b"\r\n\r\n", &self.pending) {
+                    Some(idx) => {
+                        let rest = self.pending.split_off(idx + 4);
+                        let raw = std::mem::replace(&mut self.pending, rest);
+                        self.state = Some(match self.head(&raw) {
+                            Some((head, Some(length), true)) => State::Body(head, length),
+                            Some((head, None, true)) => State::Rest(head),
+                            Some((head, length, false)) => {
+                                out.extend(head.write(None));
+                                length.map_or(State::Raw, State::Pass)
+                            }
+                            None => {
+                                out.extend(raw);
+                                State::Raw
+                            }
+                        });
+                    }
+                    None if self.pending.len() > MAX_HEAD => self.state = Some(State::Raw),
+                    None => {
+                        self.state = Some(State::Head);
+                        return out
+                    }
+                },
+                Some(State::Body(head, length)) if self.pending.len() >= length => {
+                    let rest = self.pending.split_off(length);
+                    let body = std::mem::replace(&mut self.pending, rest);
+                    let body = self.body(&head, body);
+                    out.extend(head.write(Some(body.len())));
+                    out.extend(body);
+                    self.state = Some(State::Head);
+                }
+                Some(State::Rest(head)) if self.pending.len() > MAX_BODY => {
+                    out.extend(head.write(None));
+                    self.state = Some(State::Raw);
+                }
+                Some(State::Pass(length)) => {
+                    let n = length.min(self.pending.len());
+                    out.extend(self.pending.drain(..n));
+                    if n == length {
+                        self.state = Some(State::Head);
+                    } else {
+                        self.state = Some(State::Pass(length - n));
+                        return out
+                    }
+                }
+                Some(State::Raw) => {
+                    out.append(&mut self.pending);
+                    self.state = Some(State::Raw);
+                    return out
+                }
+                other => {
+                    self.state = other;
+                    return out
+                }
+            }
+        }
+    }
+}
+
+impl StreamFilter for Responses {
+    fn on_chunk(&mut self, _conn: &Connection, chunk: &mut Vec<u8>) {
+        self.pending.append(chunk);
+        *chunk = self.parse();
+    }
+
+    fn on_eof(&mut self, _conn: &Connection) -> Vec<u8> {
+        let pending = std::mem::take(&mut self.pending);
+        match self.state.take() {
+            Some(State::Rest(head)) => {
+                let mut out = head.write(None);
+                out.extend(self.body(&head, pending));
+                out
+            }
+            // a body cut off by the end of the stream goes on as it is
+            Some(State::Body(head, _)) => {
+                let mut out = head.write(None);
+                out.extend(pending);
+                out
+            }
+            _ => pending,
+        }
+    }
+}
+
+/// `data` with the first `from` replaced by `to`, `None` when there is none.
+fn replace_first(data: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+    let idx = kmp_find(from, data).filter(|_| !from.is_empty())?;
+    let mut out = data[..idx].to_vec();
+    out.extend(to);
+    out.extend(&data[idx + from.len()..]);
+    Some(out)
+}
+
+/// `data` with every `from` replaced by `to`, `None` when there is none.
+fn replace_all(data: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+    if from.is_empty() {
+        return None
+    }
+    let mut out = Vec::new();
+    let mut rest = data;
+    while let Some(idx) = kmp_find(from, rest) {
+        out.extend(&rest[..idx]);
+        out.extend(to);
+        rest = &rest[idx + from.len()..];
+    }
+    if rest.len() == data.len() {
+        return None
+    }
+    out.extend(rest);
+    Some(out)
+}
+
+fn rfind(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).rposition(|x| x == needle)
+}
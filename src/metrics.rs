@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds (in seconds) of the connect latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Direction of a byte count, seen from the backend: `Out` is what the client
+/// wrote to the remote, `In` is what the remote sent back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, &bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Process wide counters, rendered in the prometheus text format.
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    active: AtomicI64,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+    bytes: Mutex<BTreeMap<(String, String, Direction), Arc<AtomicU64>>>,
+    connect_latency: Histogram,
+    rewrite_hits: Mutex<BTreeMap<&'static str, u64>>,
+    search_hits: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub(super) fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn connected(&self, latency: Duration) {
+        self.connect_latency.observe(latency);
+    }
+
+    pub(super) fn opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The counter of the bytes forwarded by `rule` to `backend` in
+    /// `direction`, the writers of a connection add to it as they go.
+    pub(super) fn bytes(&self, rule: &str, backend: &str, direction: Direction) -> Arc<AtomicU64> {
+        let mut bytes = self.bytes.lock().unwrap();
+        bytes.entry((rule.to_owned(), backend.to_owned(), direction)).or_default().clone()
+    }
+
+    pub(super) fn rewrite_hit(&self, rule: &'static str) {
+        *self.rewrite_hits.lock().unwrap().entry(rule).or_insert(0) += 1;
+    }
+
+    pub(super) fn search_hit(&self, pattern: &str) {
+        let mut hits = self.search_hits.lock().unwrap();
+        match hits.get_mut(pattern) {
+            Some(n) => *n += 1,
+            None => { hits.insert(pattern.to_owned(), 1); }
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP tcpforward_connections_active Connections currently being forwarded.\n");
+        out.push_str("# TYPE tcpforward_connections_active gauge\n");
+        let _ = writeln!(out, "tcpforward_connections_active {}", self.active.load(Ordering::Relaxed));
+
+        out.push_str("# HELP tcpforward_connections_accepted_total Connections accepted on the listener.\n");
+        out.push_str("# TYPE tcpforward_connections_accepted_total counter\n");
+        let _ = writeln!(out, "tcpforward_connections_accepted_total {}", self.accepted.load(Ordering::Relaxed));
+
+        out.push_str("# HELP tcpforward_connections_refused_total Connections dropped because the remote could not be reached, a first bytes rule reset them or their handshake failed.\n");
+        out.push_str("# TYPE tcpforward_connections_refused_total counter\n");
+        let _ = writeln!(out, "tcpforward_connections_refused_total {}", self.refused.load(Ordering::Relaxed));
+
+        out.push_str("# HELP tcpforward_bytes_total Bytes forwarded, by rule, backend and direction.\n");
+        out.push_str("# TYPE tcpforward_bytes_total counter\n");
+        for ((rule, backend, direction), n) in self.bytes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "tcpforward_bytes_total{{rule=\"{}\",backend=\"{}\",direction=\"{}\"}} {}",
+                escape(rule), escape(backend), direction.label(), n.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP tcpforward_connect_duration_seconds Time taken to connect to the remote.\n");
+        out.push_str("# TYPE tcpforward_connect_duration_seconds histogram\n");
+        let latency = &self.connect_latency;
+        for (bucket, bound) in latency.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(out, "tcpforward_connect_duration_seconds_bucket{{le=\"{}\"}} {}", bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = latency.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "tcpforward_connect_duration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(out, "tcpforward_connect_duration_seconds_sum {}", latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "tcpforward_connect_duration_seconds_count {}", count);
+
+        out.push_str("# HELP tcpforward_rewrite_hits_total Times a rewrite rule was applied to the stream.\n");
+        out.push_str("# TYPE tcpforward_rewrite_hits_total counter\n");
+        for (rule, n) in self.rewrite_hits.lock().unwrap().iter() {
+            let _ = writeln!(out, "tcpforward_rewrite_hits_total{{rule=\"{}\"}} {}", rule, n);
+        }
+
+        out.push_str("# HELP tcpforward_search_hits_total Times a search pattern was found.\n");
+        out.push_str("# TYPE tcpforward_search_hits_total counter\n");
+        for (pattern, n) in self.search_hits.lock().unwrap().iter() {
+            let _ = writeln!(out, "tcpforward_search_hits_total{{pattern=\"{}\"}} {}", escape(pattern), n);
+        }
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+async fn respond(mut stream: TcpStream, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let mut request = vec![0; 4096];
+    let n = stream.read(&mut request).await?;
+    let line = request[..n].split(|&b| b == b'\r' || b == b'\n').next().unwrap_or(&[]);
+
+    let response = if line.starts_with(b"GET /metrics ") || line.starts_with(b"GET / ") {
+        let body = metrics.render();
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serve the metrics over plain http on `addr` until the process exits.
+pub(super) async fn serve(addr: String, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    let date = chrono::Local::now();
+    report!("[{}] metrics are exposed on http://{}/metrics", date.format("%m-%d %H:%M"), addr);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    report!("metrics accept error: {:?}", e.to_string());
+                    tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, metrics).await {
+                    report!("metrics response error: {:?}", e.to_string());
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,500 @@
+//! Many streams over one connection between two tcpforward instances.
+//!
+//! Every frame starts with a 9 byte header: kind (1 byte), stream id and
+//! length (4 bytes each, big endian). `DATA` frames carry `length` bytes of
+//! payload, `WINDOW` frames hand `length` bytes of send credit back to the
+//! peer, `OPEN` and `CLOSE` carry nothing. A side never has more than
+//! `WINDOW` unacknowledged bytes in flight per stream, so one slow stream can
+//! not stall the others; a peer sending past it fails the session. The side
+//! that dialed uses odd stream ids, the side that accepted uses even ones, an
+//! `OPEN` of the wrong kind or for a stream in use fails the session too.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const WINDOW: u8 = 2;
+const CLOSE: u8 = 3;
+
+/// Bytes a stream may have in flight before it waits for a `WINDOW` frame.
+const STREAM_WINDOW: usize = 256 * 1024;
+
+/// Largest payload of a `DATA` frame.
+const MAX_FRAME: usize = 16 * 1024;
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    len: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, len: u32) -> Self {
+        Self { kind, id, len, payload: Vec::new() }
+    }
+
+    fn data(id: u32, payload: Vec<u8>) -> Self {
+        Self { kind: DATA, id, len: payload.len() as u32, payload }
+    }
+
+    fn header(&self) -> [u8; 9] {
+        let mut header = [0u8; 9];
+        header[0] = self.kind;
+        header[1..5].copy_from_slice(&self.id.to_be_bytes());
+        header[5..9].copy_from_slice(&self.len.to_be_bytes());
+        header
+    }
+
+    /// The kind, stream id and length of a header.
+    fn parse_header(header: &[u8; 9]) -> (u8, u32, u32) {
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        (header[0], id, len)
+    }
+}
+
+struct StreamState {
+    /// Dropped once the peer closed its side.
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Bytes received and not handed back with a `WINDOW` frame yet.
+    unacked: Arc<AtomicUsize>,
+    credit: Arc<Semaphore>,
+    /// Set once we sent `CLOSE`.
+    sent_close: bool,
+}
+
+struct Shared {
+    frames: mpsc::Sender<Frame>,
+    streams: Mutex<HashMap<u32, StreamState>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    /// Set to true to tear the session down.
+    closing: watch::Sender<bool>,
+}
+
+impl Shared {
+    /// Wire up a new stream and return the end handed to the user.
+    fn attach(self: &Arc<Self>, id: u32) -> DuplexStream {
+        let (user, mux_side) = tokio::io::duplex(STREAM_WINDOW);
+        let (mut reader, mut writer) = tokio::io::split(mux_side);
+        let credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+        let (inbound, mut chunks) = mpsc::unbounded_channel::<Vec<u8>>();
+        let unacked = Arc::new(AtomicUsize::new(0));
+        let state = StreamState { inbound: Some(inbound), unacked: unacked.clone(), credit: credit.clone(), sent_close: false };
+        self.streams.lock().unwrap().insert(id, state);
+
+        let frames = self.frames.clone();
+        let shared = self.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_FRAME];
+            loop {
+                let n = match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                match credit.acquire_many(n as u32).await {
+                    Ok(permit) => permit.forget(),
+                    // the session is gone
+                    Err(_) => return,
+                }
+                if frames.send(Frame::data(id, buf[..n].to_vec())).await.is_err() {
+                    return
+                }
+            }
+            let _ = frames.send(Frame::new(CLOSE, id, 0)).await;
+            let mut streams = shared.streams.lock().unwrap();
+            if let Some(stream) = streams.get_mut(&id) {
+                if stream.inbound.is_none() {
+                    streams.remove(&id);
+                } else {
+                    stream.sent_close = true;
+                }
+            }
+        });
+
+        let frames = self.frames.clone();
+        tokio::spawn(async move {
+            while let Some(chunk) = chunks.recv().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    break
+                }
+                unacked.fetch_sub(chunk.len(), Ordering::Relaxed);
+                if frames.send(Frame::new(WINDOW, id, chunk.len() as u32)).await.is_err() {
+                    break
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        user
+    }
+
+    fn shut(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, stream) in self.streams.lock().unwrap().drain() {
+            stream.credit.close();
+        }
+    }
+}
+
+/// One multiplexed connection.
+#[derive(Clone)]
+pub(super) struct Session {
+    shared: Arc<Shared>,
+}
+
+impl Session {
+    /// Run the framing over `conn`. Streams opened by the peer are delivered
+    /// on the returned receiver.
+    pub(super) fn new<S>(conn: S, dialer: bool) -> (Self, mpsc::UnboundedReceiver<DuplexStream>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(conn);
+        let (frames, mut outgoing) = mpsc::channel::<Frame>(64);
+        let (accepted, incoming) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            frames,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(if dialer { 1 } else { 2 }),
+            closed: AtomicBool::new(false),
+            closing: watch::channel(false).0,
+        });
+
+        let writing = shared.clone();
+        let mut closing = shared.closing.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = outgoing.recv() => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = closing.wait_for(|x| *x) => break,
+                };
+                if writer.write_all(&frame.header()).await.is_err()
+                    || writer.write_all(&frame.payload).await.is_err()
+                    || writer.flush().await.is_err() {
+                    break
+                }
+            }
+            let _ = writer.shutdown().await;
+            writing.shut();
+        });
+
+        let reading = shared.clone();
+        let mut closing = shared.closing.subscribe();
+        tokio::spawn(async move {
+            let frames = async {
+                loop {
+                    let mut header = [0u8; 9];
+                    reader.read_exact(&mut header).await?;
+                    let (kind, id, len) = Frame::parse_header(&header);
+                    match kind {
+                        OPEN => {
+                            // the peer opens the ids of the other parity
+                            if id % 2 == u32::from(dialer) || reading.streams.lock().unwrap().contains_key(&id) {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("mux: the peer can not open stream {}", id)))
+                            }
+                            let stream = reading.attach(id);
+                            let _ = accepted.send(stream);
+                        }
+                        DATA => {
+                            if len as usize > MAX_FRAME {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "mux: frame is too large"))
+                            }
+                            let mut payload = vec![0u8; len as usize];
+                            reader.read_exact(&mut payload).await?;
+                            let streams = reading.streams.lock().unwrap();
+                            if let Some(stream) = streams.get(&id) {
+                                if stream.unacked.fetch_add(payload.len(), Ordering::Relaxed) + payload.len() > STREAM_WINDOW {
+                                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("mux: stream {} was sent past its window", id)))
+                                }
+                                if let Some(inbound) = &stream.inbound {
+                                    let _ = inbound.send(payload);
+                                }
+                            }
+                        }
+                        WINDOW => {
+                            if let Some(stream) = reading.streams.lock().unwrap().get(&id) {
+                                stream.credit.add_permits(len as usize);
+                            }
+                        }
+                        CLOSE => {
+                            let mut streams = reading.streams.lock().unwrap();
+                            if let Some(stream) = streams.get_mut(&id) {
+                                if stream.sent_close {
+                                    streams.remove(&id);
+                                } else {
+                                    stream.inbound = None;
+                                }
+                            }
+                        }
+                        kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("mux: unknown frame kind {}", kind))),
+                    }
+                }
+            };
+            let result: io::Result<()> = tokio::select! {
+                result = frames => result,
+                _ = closing.wait_for(|x| *x) => Ok(()),
+            };
+            if let Err(e) = result {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    report!("mux session error: {:?}", e.to_string());
+                }
+            }
+            reading.shut();
+        });
+
+        (Self { shared }, incoming)
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    /// Tear the session down, with every stream on it.
+    pub(super) fn close(&self) {
+        self.shared.closing.send_replace(true);
+        self.shared.shut();
+    }
+
+    /// Whether both are handles of the same session.
+    pub(super) fn same(&self, other: &Session) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Open a new stream to the peer.
+    pub(super) async fn open(&self) -> io::Result<DuplexStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "mux: session is closed"))
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.attach(id);
+        self.shared.frames.send(Frame::new(OPEN, id, 0)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "mux: session is closed"))?;
+        Ok(stream)
+    }
+}
+
+/// Keeps one session to the peer and reconnects it when it breaks.
+#[derive(Default)]
+pub(super) struct Dialer {
+    session: Mutex<Option<Session>>,
+}
+
+impl Dialer {
+    /// Open a stream, first dialing a new session with `connect` if needed.
+    pub(super) async fn open<F, Fut, S>(&self, connect: F) -> io::Result<DuplexStream>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let current = self.session.lock().unwrap().clone();
+        if let Some(current) = current.filter(|x| !x.is_closed()) {
+            return current.open().await
+        }
+        // not under the lock, a slow dial must not hold up the streams of a
+        // session another client dialed meanwhile
+        let (fresh, _) = Session::new(connect().await?, true);
+        let session = {
+            let mut session = self.session.lock().unwrap();
+            match &*session {
+                Some(current) if !current.is_closed() => {
+                    fresh.close();
+                    current.clone()
+                }
+                _ => {
+                    let date = chrono::Local::now();
+                    report!("[{}] mux session is established", date.format("%m-%d %H:%M"));
+                    *session = Some(fresh.clone());
+                    fresh
+                }
+            }
+        };
+        session.open().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn pair() -> (Session, Session, mpsc::UnboundedReceiver<DuplexStream>) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (dialer, _) = Session::new(a, true);
+        let (acceptor, accepted) = Session::new(b, false);
+        (dialer, acceptor, accepted)
+    }
+
+    #[test]
+    fn frame_headers_read_back_as_written() {
+        let frame = Frame::new(WINDOW, 0x01020304, 0x0a0b0c0d);
+        let header = frame.header();
+        assert_eq!(header, [WINDOW, 1, 2, 3, 4, 0x0a, 0x0b, 0x0c, 0x0d]);
+        assert_eq!(Frame::parse_header(&header), (WINDOW, 0x01020304, 0x0a0b0c0d));
+        assert_eq!(Frame::parse_header(&Frame::data(7, vec![0; 5]).header()), (DATA, 7, 5));
+    }
+
+    #[tokio::test]
+    async fn streams_carry_both_directions_and_close_each_on_its_own() {
+        let (dialer, _acceptor, mut accepted) = pair();
+        let mut ours = dialer.open().await.unwrap();
+        let mut theirs = accepted.recv().await.unwrap();
+
+        ours.write_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        // closing one side still lets the other one talk
+        ours.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        theirs.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        theirs.write_all(b"bye").await.unwrap();
+        theirs.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        ours.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn a_stream_nobody_reads_does_not_stall_the_others() {
+        let (dialer, _acceptor, mut accepted) = pair();
+        let mut slow = dialer.open().await.unwrap();
+        let mut slow_peer = accepted.recv().await.unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let counting = written.clone();
+        let writer = tokio::spawn(async move {
+            for _ in 0..256 {
+                slow.write_all(&[7; 16 * 1024]).await.unwrap();
+                counting.fetch_add(16 * 1024, Ordering::Relaxed);
+            }
+            slow.shutdown().await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // the credit window and the buffers of both ends, not all 4 MiB
+        let stuck = written.load(Ordering::Relaxed);
+        assert!(stuck < 4 * STREAM_WINDOW, "{} bytes went out", stuck);
+
+        let mut fast = dialer.open().await.unwrap();
+        let mut fast_peer = accepted.recv().await.unwrap();
+        fast.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        tokio::time::timeout(Duration::from_secs(1), fast_peer.read_exact(&mut received)).await.unwrap().unwrap();
+        assert_eq!(&received, b"ping");
+
+        let mut received = Vec::new();
+        slow_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 256 * 16 * 1024);
+        assert!(received.iter().all(|&x| x == 7));
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_the_session_ends_its_streams() {
+        let (dialer, acceptor, mut accepted) = pair();
+        let mut ours = dialer.open().await.unwrap();
+        let mut theirs = accepted.recv().await.unwrap();
+        dialer.close();
+        assert!(dialer.is_closed());
+        assert!(dialer.open().await.is_err());
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), ours.read_to_end(&mut received)).await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), theirs.read_to_end(&mut received)).await.unwrap().unwrap();
+        assert!(received.is_empty());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(acceptor.is_closed());
+    }
+
+    #[tokio::test]
+    async fn the_dialer_reuses_its_session_and_redials_a_closed_one() {
+        let dialer = Dialer::default();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let connect = || {
+            let dials = dials.clone();
+            async move {
+                dials.fetch_add(1, Ordering::Relaxed);
+                let (ours, theirs) = tokio::io::duplex(1024);
+                // keep the far end open for the session to last
+                tokio::spawn(async move {
+                    let (_session, mut streams) = Session::new(theirs, false);
+                    while streams.recv().await.is_some() {}
+                });
+                Ok::<_, io::Error>(ours)
+            }
+        };
+        dialer.open(connect).await.unwrap();
+        dialer.open(connect).await.unwrap();
+        assert_eq!(dials.load(Ordering::Relaxed), 1);
+        dialer.session.lock().unwrap().as_ref().unwrap().close();
+        dialer.open(connect).await.unwrap();
+        assert_eq!(dials.load(Ordering::Relaxed), 2);
+    }
+
+    /// A session accepting on one end and the raw other end, for a peer that
+    /// does not play by the rules.
+    fn raw_peer() -> (Session, tokio::io::DuplexStream) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (session, accepted) = Session::new(a, false);
+        // keep the accepted streams, unread
+        tokio::spawn(async move {
+            let mut accepted = accepted;
+            let mut streams = Vec::new();
+            while let Some(stream) = accepted.recv().await {
+                streams.push(stream);
+            }
+        });
+        (session, b)
+    }
+
+    async fn closes_on(frames: Vec<Frame>) -> bool {
+        let (session, mut peer) = raw_peer();
+        tokio::spawn(async move {
+            for frame in frames {
+                let _ = peer.write_all(&frame.header()).await;
+                let _ = peer.write_all(&frame.payload).await;
+            }
+            // never reads, never closes
+            std::future::pending::<()>().await;
+        });
+        for _ in 0..50 {
+            if session.is_closed() {
+                return true
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn a_peer_sending_past_the_window_fails_the_session() {
+        let mut frames = vec![Frame::new(OPEN, 1, 0)];
+        frames.extend((0..STREAM_WINDOW / MAX_FRAME).map(|_| Frame::data(1, vec![0; MAX_FRAME])));
+        assert!(!closes_on(frames).await);
+
+        let mut frames = vec![Frame::new(OPEN, 1, 0)];
+        frames.extend((0..3 * STREAM_WINDOW / MAX_FRAME).map(|_| Frame::data(1, vec![0; MAX_FRAME])));
+        assert!(closes_on(frames).await);
+    }
+
+    #[tokio::test]
+    async fn the_peer_only_opens_new_streams_of_its_own() {
+        assert!(!closes_on(vec![Frame::new(OPEN, 1, 0), Frame::new(OPEN, 3, 0)]).await);
+        assert!(closes_on(vec![Frame::new(OPEN, 1, 0), Frame::new(OPEN, 1, 0)]).await);
+        assert!(closes_on(vec![Frame::new(OPEN, 2, 0)]).await);
+    }
+}
@@ -0,0 +1,176 @@
+//! Addresses, name resolution and connecting.
+//!
+//! Remote names are resolved through one shared resolver, which caches the
+//! answers for as long as their TTL allows. Connecting to a name with several
+//! addresses races them happy-eyeballs style (RFC 8305): IPv6 and IPv4
+//! addresses alternate, and the next attempt starts whenever the previous one
+//! failed or has not finished within `ATTEMPT_DELAY`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use hickory_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::mpsc;
+
+/// Head start of a connection attempt before the next address is tried.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolved names kept in the cache.
+const CACHE_SIZE: usize = 1024;
+
+/// How long an accept loop waits after an error, like running out of file
+/// descriptors, before it tries again.
+pub(super) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Split `host:port` or `[v6]:port` into its host and port.
+pub(super) fn split_host_port(target: &str) -> Option<(&str, u16)> {
+    let idx = target.rfind(':')?;
+    let port = target[idx + 1..].parse().ok()?;
+    let host = target[..idx].trim_start_matches('[').trim_end_matches(']');
+    Some((host, port))
+}
+
+/// The inverse of `split_host_port`, IPv6 literals get their brackets.
+pub(super) fn join_host_port(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn resolver() -> &'static TokioAsyncResolver {
+    static RESOLVER: OnceLock<TokioAsyncResolver> = OnceLock::new();
+    RESOLVER.get_or_init(|| {
+        let (config, mut opts) = match hickory_resolver::system_conf::read_system_conf() {
+            Ok(conf) => conf,
+            Err(e) => {
+                report!("reading the system dns configuration error: {:?}", e.to_string());
+                (ResolverConfig::default(), ResolverOpts::default())
+            }
+        };
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        opts.cache_size = CACHE_SIZE;
+        TokioAsyncResolver::tokio(config, opts)
+    })
+}
+
+/// All addresses of `host`, IPv6 and IPv4 interleaved with IPv6 first.
+pub(super) async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)])
+    }
+    let lookup = resolver().lookup_ip(host).await
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("resolving {}: {}", host, e)))?;
+    let (mut v6, mut v4): (Vec<IpAddr>, Vec<IpAddr>) = lookup.iter().partition(IpAddr::is_ipv6);
+    v6.reverse();
+    v4.reverse();
+    let mut addrs = Vec::new();
+    while let Some(ip) = v6.pop().or_else(|| v4.pop()) {
+        addrs.push(SocketAddr::new(ip, port));
+        if let Some(ip) = v4.pop() {
+            addrs.push(SocketAddr::new(ip, port));
+        }
+    }
+    Ok(addrs)
+}
+
+/// Connect to `host:port`, racing its addresses.
+pub(super) async fn connect(target: &str) -> io::Result<TcpStream> {
+    let (host, port) = split_host_port(target)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} has no port", target)))?;
+    let mut addrs = resolve(host, port).await?.into_iter();
+
+    let (results, mut finished) = mpsc::unbounded_channel();
+    let mut attempts = Vec::new();
+    let mut running = 0;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host));
+    let result = loop {
+        if let Some(addr) = addrs.next() {
+            let results = results.clone();
+            attempts.push(tokio::spawn(async move {
+                let _ = results.send(TcpStream::connect(addr).await);
+            }));
+            running += 1;
+        }
+        if running == 0 {
+            break Err(last_error)
+        }
+        tokio::select! {
+            Some(result) = finished.recv() => {
+                running -= 1;
+                match result {
+                    Ok(stream) => break Ok(stream),
+                    // try the next address right away
+                    Err(e) => last_error = e,
+                }
+            }
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if addrs.len() > 0 => {}
+        }
+    };
+    for attempt in attempts {
+        attempt.abort();
+    }
+    result
+}
+
+#[cfg(unix)]
+fn set_only_v6(socket: &tokio::net::TcpSocket, only_v6: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value: libc::c_int = only_v6.into();
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_only_v6(_: &tokio::net::TcpSocket, _: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Listen on `addr`. An IPv6 address also takes IPv4 connections, as
+/// v4-mapped addresses, unless `only_v6` is set.
+pub(super) fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    if addr.is_ipv6() {
+        set_only_v6(&socket, only_v6)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Listen on the unix socket at `path`. A socket left there by a previous run
+/// is removed, one something still listens on and anything else at `path`
+/// are left alone.
+pub(super) fn bind_unix(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path))),
+            Err(e) => return Err(e),
+        },
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} exists and is no socket", path))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
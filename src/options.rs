@@ -0,0 +1,234 @@
+use structopt::StructOpt;
+
+use std::path::PathBuf;
+
+use crate::{action, compress, first_bytes, proxy_protocol, search, upstream};
+
+/// A simple tcp forwarding tool
+///
+/// Every command line option, `Builder::options` gives access to the ones
+/// without a builder method of their own.
+#[derive(StructOpt, Debug, Default)]
+#[structopt(name = "tcpforward")]
+pub struct Options {
+    /// local ip, repeat it to listen on several addresses; `::` also takes ipv4
+    /// connections unless an ipv4 address is given as well
+    #[structopt(long, required_unless_one = &["tunnel-server", "local-unix"], requires = "local-port", number_of_values = 1)]
+    pub local_ip: Vec<String>,
+
+    /// local port
+    #[structopt(long, required_unless_one = &["tunnel-server", "local-unix"])]
+    pub local_port: Option<u16>,
+
+    /// listen on a unix socket at this path, next to or instead of --local-ip
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect", "transparent", "tproxy", "accept-proxy", "tunnel-listen"])]
+    pub local_unix: Option<String>,
+
+    /// remote ip or host name, names are resolved on every connect (cached by their TTL)
+    #[structopt(long, required_unless_one = &["transparent", "tproxy", "socks5", "http-connect", "tunnel-listen", "remote-unix"])]
+    pub remote_ip: Option<String>,
+
+    /// remote port
+    #[structopt(long, required_unless_one = &["transparent", "tproxy", "socks5", "http-connect", "tunnel-listen", "remote-unix"])]
+    pub remote_port: Option<u16>,
+
+    /// forward to the unix socket at this path instead of --remote-ip and --remote-port
+    #[structopt(long, conflicts_with_all = &["remote-ip", "remote-port", "via"])]
+    pub remote_unix: Option<String>,
+
+    /// password
+    #[structopt(long)]
+    pub password: Option<String>,
+
+    /// log the web UI of the remotes in with the auto-login profiles of this file instead of
+    /// the DVR login of --password
+    #[structopt(long)]
+    pub login_profiles: Option<String>,
+
+    /// search, start the pattern with out: for what the client sends, in: for what the
+    /// remote sends or both: (repeatable)
+    #[structopt(long)]
+    pub search: Vec<String>,
+
+    /// blocking mode, swallow what a client sends unless it starts with # or ~, judged
+    /// by its first chunk whenever it comes; clients a --first-bytes rule decided about
+    /// are left alone
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect"])]
+    pub blocking_mode: bool,
+
+    /// decide by the first bytes a client sends, <matcher>=<action> with the matchers
+    /// magic:<hex>, line:<regex>, tls, http[:<method>,...], ssh, rtsp, silent or any and
+    /// the actions pass, drop, reset or redirect:<remote>; the first rule that matches
+    /// decides (repeatable)
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect"])]
+    pub first_bytes: Vec<first_bytes::Rule>,
+
+    /// share the port between protocols like sslh: relay the clients a first bytes matcher
+    /// like http, tls, ssh, rtsp or magic:<hex> finds to their own remote, <matcher>=<remote>;
+    /// checked after --first-bytes, other clients go to the remote (repeatable)
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect", "tunnel-listen"])]
+    pub route: Vec<first_bytes::Route>,
+
+    /// how long to wait for the first bytes of a client in milliseconds, as long as a rule
+    /// can not tell yet, before it is judged by what it sent or as silent (default 5000)
+    #[structopt(long)]
+    pub first_bytes_timeout: Option<u64>,
+
+    /// pattern or
+    #[structopt(long)]
+    pub pattern_or: bool,
+
+    /// search with a regular expression, it may start with out:, in: or both: like
+    /// --search (repeatable)
+    #[structopt(long)]
+    pub search_regex: Vec<String>,
+
+    /// mark a connection matched only when all search patterns are found within this
+    /// many bytes of its traffic, instead of anywhere in it
+    #[structopt(long)]
+    pub search_window: Option<u64>,
+
+    /// match the search patterns regardless of case
+    #[structopt(long)]
+    pub ignore_case: bool,
+
+    /// bytes of context shown before and after a search hit (default 64)
+    #[structopt(long)]
+    pub context: Option<usize>,
+
+    /// how search hits are logged: text, hex or json with the bytes in base64 (default text)
+    #[structopt(long)]
+    pub hit_format: Option<search::HitFormat>,
+
+    /// act on every hit of a search pattern, <pattern>=<action>[,<action>...] with the
+    /// pattern as given to --search or --search-regex and the actions capture, webhook,
+    /// kill or block (repeatable)
+    #[structopt(long)]
+    pub search_action: Vec<String>,
+
+    /// post search hits with the webhook action to this http://host:port/path as json
+    #[structopt(long)]
+    pub webhook: Option<action::Webhook>,
+
+    /// directory the capture action writes to (default captures)
+    #[structopt(long, parse(from_os_str))]
+    pub capture_dir: Option<PathBuf>,
+
+    /// remove-options-mode
+    #[structopt(long)]
+    pub remove_options: bool,
+
+    /// expose prometheus metrics over http on this address, e.g. 127.0.0.1:9100
+    #[structopt(long)]
+    pub metrics_listen: Option<String>,
+
+    /// serve the admin api on a loopback ip:port or on unix:<path>
+    #[structopt(long)]
+    pub admin_listen: Option<String>,
+
+    /// show a live dashboard of the connections instead of the log
+    #[structopt(long)]
+    pub tui: bool,
+
+    /// announce the real client address to the remote with a PROXY protocol header (v1 or v2)
+    #[structopt(long)]
+    pub send_proxy: Option<proxy_protocol::Version>,
+
+    /// expect a PROXY protocol header (v1 or v2) on every accepted connection
+    #[structopt(long)]
+    pub accept_proxy: bool,
+
+    /// connect to the original destination of connections redirected here by iptables REDIRECT
+    #[structopt(long)]
+    pub transparent: bool,
+
+    /// listen with IP_TRANSPARENT and connect to the original destination of TPROXY diverted connections
+    #[structopt(long)]
+    pub tproxy: bool,
+
+    /// act as a socks5 server and connect to whatever destination the client asks for
+    #[structopt(long)]
+    pub socks5: bool,
+
+    /// username the socks5 clients have to authenticate with
+    #[structopt(long, requires = "socks-password")]
+    pub socks_user: Option<String>,
+
+    /// password the socks5 clients have to authenticate with
+    #[structopt(long, requires = "socks-user")]
+    pub socks_password: Option<String>,
+
+    /// act as an http proxy that only accepts CONNECT requests
+    #[structopt(long, conflicts_with = "socks5")]
+    pub http_connect: bool,
+
+    /// destination the http proxy may connect to, like example.com:443, *.example.com:443 or *:22 (repeatable, default any)
+    #[structopt(long)]
+    pub allow: Vec<String>,
+
+    /// username the http proxy clients have to authenticate with
+    #[structopt(long, requires = "proxy-password")]
+    pub proxy_user: Option<String>,
+
+    /// password the http proxy clients have to authenticate with
+    #[structopt(long, requires = "proxy-user")]
+    pub proxy_password: Option<String>,
+
+    /// reach the remote through a proxy, socks5://[user:password@]host:port or http://[user:password@]host:port
+    #[structopt(long)]
+    pub via: Option<upstream::Via>,
+
+    /// run the public side of a reverse tunnel: wait for an agent knowing --psk on this
+    /// address and forward the clients accepted on the local port through it
+    #[structopt(long, requires = "psk", conflicts_with_all = &["socks5", "http-connect", "transparent", "tproxy", "tunnel-server"])]
+    pub tunnel_listen: Option<String>,
+
+    /// run the agent side of a reverse tunnel: keep a connection encrypted with --psk to
+    /// this tunnel server open and forward the streams it opens to the remote
+    #[structopt(long, requires = "psk")]
+    pub tunnel_server: Option<String>,
+
+    /// carry all connections to the remote as streams of one long-lived connection,
+    /// the remote has to be a tcpforward running with --mux-accept
+    #[structopt(long, conflicts_with = "tunnel-listen")]
+    pub mux: bool,
+
+    /// accept multiplexed connections from a tcpforward running with --mux and
+    /// forward each of their streams to the remote
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect", "transparent", "tproxy", "accept-proxy", "tunnel-listen"])]
+    pub mux_accept: bool,
+
+    /// pre-shared key of the encrypted link between two tcpforward instances, and of
+    /// the reverse tunnel
+    #[structopt(long)]
+    pub psk: Option<String>,
+
+    /// encrypt the connections to the remote with --psk, the remote has to be a
+    /// tcpforward running with --secure-local
+    #[structopt(long, requires = "psk", conflicts_with = "tunnel-listen")]
+    pub secure_remote: bool,
+
+    /// only accept connections encrypted with --psk by a tcpforward running with
+    /// --secure-remote
+    #[structopt(long, requires = "psk", conflicts_with_all = &["socks5", "http-connect", "transparent", "tproxy", "accept-proxy", "tunnel-listen"])]
+    pub secure_local: bool,
+
+    /// compress the connections to the remote with zstd or deflate, the remote has
+    /// to be a tcpforward running with --compress-local
+    #[structopt(long)]
+    pub compress_remote: Option<compress::Algorithm>,
+
+    /// accept connections compressed by a tcpforward running with --compress-remote
+    #[structopt(long, conflicts_with_all = &["socks5", "http-connect", "transparent", "tproxy", "accept-proxy", "tunnel-listen"])]
+    pub compress_local: bool,
+
+    /// run both directions of every connection through a rhai script, reloaded
+    /// when the file changes (repeatable)
+    #[structopt(long)]
+    pub script: Vec<String>,
+
+    /// run both directions of every connection through a WebAssembly filter plugin,
+    /// needs the wasm feature (repeatable)
+    #[structopt(long)]
+    pub wasm: Vec<String>,
+}
@@ -0,0 +1,193 @@
+//! Filters compiled to WebAssembly and loaded with --wasm.
+//!
+//! Every connection and direction gets an instance of its own. The module
+//! exports `memory` and `alloc(len: i32) -> i32`, which the host calls for
+//! room to pass data in, and any of:
+//!
+//! * `on_connect(ptr: i32, len: i32)`, the connection as a json object with
+//!   `id`, `peer`, `local_port`, `backend` and `direction` ("out" or "in");
+//! * `on_data(ptr: i32, len: i32) -> i64` with every chunk;
+//! * `on_close() -> i64` once the sending side is done.
+//!
+//! `on_data` returns -1 to pass the chunk on unchanged, or `ptr << 32 | len`
+//! of the data to pass on instead, a length of 0 drops the chunk. `on_close`
+//! returns data to inject before the end of the stream the same way. The
+//! module may import `env.log(ptr: i32, len: i32)` to print a line.
+//!
+//! A call that traps or runs out of fuel is reported and turns the filter of
+//! that direction off, the data passes on unchanged from then on.
+
+use std::io;
+use std::sync::Arc;
+
+use wasmtime::{Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::admin::json_string;
+use crate::filter::StreamFilter;
+use crate::metrics::Direction;
+use crate::registry::Connection;
+
+/// How much a single call may compute, a loop in a plugin must not hang the
+/// connection.
+const FUEL: u64 = 100_000_000;
+
+/// A compiled plugin, instantiated for every connection and direction.
+pub(super) struct Plugin {
+    path: Arc<str>,
+    engine: Engine,
+    module: Module,
+    linker: Linker<Arc<str>>,
+}
+
+impl Plugin {
+    pub(super) fn load(path: &str) -> io::Result<Arc<Self>> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| io::Error::other(e.to_string()))?;
+        let module = Module::from_file(&engine, path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap("env", "log", |mut caller: Caller<'_, Arc<str>>, ptr: i32, len: i32| {
+            let memory = match caller.get_export("memory") {
+                Some(Extern::Memory(memory)) => memory,
+                _ => return,
+            };
+            if let Some(data) = read(&memory, &caller, ptr, len) {
+                let date = chrono::Local::now();
+                report!("[{}] {}: {}", date.format("%m-%d %H:%M"), caller.data(), String::from_utf8_lossy(&data));
+            }
+        }).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Arc::new(Self { path: path.into(), engine, module, linker }))
+    }
+
+    /// The filter of one direction for a new connection.
+    pub(super) fn filter(&self, conn: &Connection, direction: Direction) -> Box<dyn StreamFilter> {
+        let mut store = Store::new(&self.engine, self.path.clone());
+        let instance = match self.linker.instantiate(&mut store, &self.module) {
+            Ok(instance) => instance,
+            Err(e) => {
+                report!("plugin {} error: {:?}", self.path, e.to_string());
+                return Box::new(PluginFilter { store, exports: None })
+            }
+        };
+        let exports = match Exports::get(&instance, &mut store) {
+            Some(exports) => exports,
+            None => {
+                report!("plugin {} error: {:?}", self.path, "it has to export memory and alloc");
+                return Box::new(PluginFilter { store, exports: None })
+            }
+        };
+        let mut filter = PluginFilter { store, exports: Some(exports) };
+        let meta = format!(
+            "{{\"id\":{},\"peer\":{},\"local_port\":{},\"backend\":{},\"direction\":\"{}\"}}",
+            conn.id(),
+            json_string(&conn.peer().to_string()),
+            conn.local_port(),
+            json_string(conn.backend()),
+            match direction { Direction::Out => "out", Direction::In => "in" },
+        );
+        if let Some(on_connect) = filter.exports.as_ref().and_then(|x| x.on_connect.clone()) {
+            filter.guard("on_connect", |store, exports| {
+                let (ptr, len) = exports.write(store, meta.as_bytes())?;
+                on_connect.call(store, (ptr, len))
+            });
+        }
+        Box::new(filter)
+    }
+}
+
+/// What the host calls in an instance.
+struct Exports {
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_connect: Option<TypedFunc<(i32, i32), ()>>,
+    on_data: Option<TypedFunc<(i32, i32), i64>>,
+    on_close: Option<TypedFunc<(), i64>>,
+}
+
+impl Exports {
+    fn get(instance: &Instance, store: &mut Store<Arc<str>>) -> Option<Self> {
+        Some(Self {
+            memory: instance.get_memory(&mut *store, "memory")?,
+            alloc: instance.get_typed_func(&mut *store, "alloc").ok()?,
+            on_connect: instance.get_typed_func(&mut *store, "on_connect").ok(),
+            on_data: instance.get_typed_func(&mut *store, "on_data").ok(),
+            on_close: instance.get_typed_func(&mut *store, "on_close").ok(),
+        })
+    }
+
+    /// Copy `data` into the instance, returning where it is.
+    fn write(&self, store: &mut Store<Arc<str>>, data: &[u8]) -> wasmtime::Result<(i32, i32)> {
+        let ptr = self.alloc.call(&mut *store, data.len() as i32)?;
+        self.memory.write(&mut *store, ptr as u32 as usize, data)?;
+        Ok((ptr, data.len() as i32))
+    }
+
+    /// The data a call returned as `ptr << 32 | len`, `None` for -1.
+    fn result(&self, store: &Store<Arc<str>>, packed: i64) -> wasmtime::Result<Option<Vec<u8>>> {
+        if packed == -1 {
+            return Ok(None)
+        }
+        read(&self.memory, store, (packed >> 32) as i32, packed as i32)
+            .map(Some)
+            .ok_or_else(|| wasmtime::Error::msg("returned data is out of bounds"))
+    }
+}
+
+fn read(memory: &Memory, store: impl wasmtime::AsContext, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let start = ptr as u32 as usize;
+    memory.data(&store).get(start..start.checked_add(len as u32 as usize)?).map(<[u8]>::to_vec)
+}
+
+struct PluginFilter {
+    store: Store<Arc<str>>,
+    /// `None` once the plugin failed.
+    exports: Option<Exports>,
+}
+
+impl PluginFilter {
+    /// Run `call` with fresh fuel, turning the filter off when it fails.
+    fn guard<T>(&mut self, name: &str, call: impl FnOnce(&mut Store<Arc<str>>, &Exports) -> wasmtime::Result<T>) -> Option<T> {
+        let exports = self.exports.as_ref()?;
+        let store = &mut self.store;
+        let result = store.set_fuel(FUEL).and_then(|_| call(store, exports));
+        match result {
+            Ok(x) => Some(x),
+            Err(e) => {
+                report!("plugin {} {} error: {:?}", self.store.data(), name, e.to_string());
+                self.exports = None;
+                None
+            }
+        }
+    }
+}
+
+impl StreamFilter for PluginFilter {
+    fn on_chunk(&mut self, _conn: &Connection, chunk: &mut Vec<u8>) {
+        let on_data = match self.exports.as_ref().and_then(|x| x.on_data.clone()) {
+            Some(on_data) => on_data,
+            None => return,
+        };
+        let data: &[u8] = chunk;
+        let result = self.guard("on_data", |store, exports| {
+            let (ptr, len) = exports.write(store, data)?;
+            let packed = on_data.call(&mut *store, (ptr, len))?;
+            exports.result(store, packed)
+        });
+        if let Some(Some(data)) = result {
+            *chunk = data;
+        }
+    }
+
+    fn on_eof(&mut self, _conn: &Connection) -> Vec<u8> {
+        let on_close = match self.exports.as_ref().and_then(|x| x.on_close.clone()) {
+            Some(on_close) => on_close,
+            None => return Vec::new(),
+        };
+        let result = self.guard("on_close", |store, exports| {
+            let packed = on_close.call(&mut *store, ())?;
+            exports.result(store, packed)
+        });
+        result.flatten().unwrap_or_default()
+    }
+}
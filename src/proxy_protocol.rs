@@ -0,0 +1,249 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest v1 header allowed by the spec, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// The longest v2 address block taken, real ones with TLVs are far shorter.
+const V2_MAX_LEN: usize = 4096;
+
+/// Version of the PROXY protocol header sent to the remote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(Version::V1),
+            "v2" | "2" => Ok(Version::V2),
+            _ => Err(format!("unknown proxy protocol version {:?}, expected v1 or v2", s)),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad proxy protocol header: {}", message))
+}
+
+/// Put both addresses in the same family, v4 is only used when both are v4.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    fn v6(addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+            IpAddr::V6(_) => addr,
+        }
+    }
+    if src.is_ipv4() && dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (v6(src), v6(dst))
+    }
+}
+
+/// Build the header announcing a connection from `src` (the real client) to `dst`.
+pub(super) fn header(version: Version, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+    match version {
+        Version::V1 => {
+            let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {} {} {} {} {}\r\n", family, src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+        }
+        Version::V2 => {
+            let mut header = Vec::from(&V2_SIGNATURE[..]);
+            // version 2, PROXY command
+            header.push(0x21);
+            match (src.ip(), dst.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    header.push(0x11);
+                    header.extend(&12u16.to_be_bytes());
+                    header.extend(&s.octets());
+                    header.extend(&d.octets());
+                }
+                (IpAddr::V6(s), IpAddr::V6(d)) => {
+                    header.push(0x21);
+                    header.extend(&36u16.to_be_bytes());
+                    header.extend(&s.octets());
+                    header.extend(&d.octets());
+                }
+                _ => unreachable!("addresses are converted to the same family"),
+            }
+            header.extend(&src.port().to_be_bytes());
+            header.extend(&dst.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// Build the header of a connection whose addresses are not known, like one
+/// from a unix socket: `UNKNOWN` in v1, the LOCAL command in v2.
+pub(super) fn header_unknown(version: Version) -> Vec<u8> {
+    match version {
+        Version::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        Version::V2 => {
+            let mut header = Vec::from(&V2_SIGNATURE[..]);
+            // version 2, LOCAL command, unspecified family, no addresses
+            header.extend(&[0x20, 0x00, 0x00, 0x00]);
+            header
+        }
+    }
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, dst, src_port, dst_port] | ["PROXY", "TCP6", src, dst, src_port, dst_port] => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("bad address"))?;
+                let port: u16 = port.parse().map_err(|_| invalid("bad port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some((addr(src, src_port)?, addr(dst, dst_port)?)))
+        }
+        _ => Err(invalid("unsupported v1 line")),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, body: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    if command >> 4 != 2 {
+        return Err(invalid("unsupported version"))
+    }
+    // LOCAL command, the connection was made by the proxy itself
+    if command & 0x0f == 0 {
+        return Ok(None)
+    }
+    match family {
+        0x11 if body.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&body[0..4]);
+            let src = Ipv4Addr::from(ip);
+            ip.copy_from_slice(&body[4..8]);
+            let dst = Ipv4Addr::from(ip);
+            let src_port = u16::from_be_bytes([body[8], body[9]]);
+            let dst_port = u16::from_be_bytes([body[10], body[11]]);
+            Ok(Some((SocketAddr::new(src.into(), src_port), SocketAddr::new(dst.into(), dst_port))))
+        }
+        0x21 if body.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&body[0..16]);
+            let src = Ipv6Addr::from(ip);
+            ip.copy_from_slice(&body[16..32]);
+            let dst = Ipv6Addr::from(ip);
+            let src_port = u16::from_be_bytes([body[32], body[33]]);
+            let dst_port = u16::from_be_bytes([body[34], body[35]]);
+            Ok(Some((SocketAddr::new(src.into(), src_port), SocketAddr::new(dst.into(), dst_port))))
+        }
+        // unix sockets and unspecified families carry nothing we can use
+        _ => Ok(None),
+    }
+}
+
+/// Read a v1 or v2 header from the start of `reader`, consuming nothing past it.
+///
+/// Returns the client and destination addresses the header announces, or
+/// `None` when the sending proxy did not provide them.
+pub(super) async fn read_header<R>(reader: &mut R) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start[..5]).await?;
+
+    if &start[..5] == b"PROXY" {
+        let mut line = Vec::from(&start[..5]);
+        loop {
+            let byte = reader.read_u8().await?;
+            line.push(byte);
+            if line.ends_with(b"\r\n") {
+                break
+            }
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 line is too long"))
+            }
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("v1 line is not text"))?;
+        return parse_v1(line)
+    }
+
+    reader.read_exact(&mut start[5..]).await?;
+    if start != V2_SIGNATURE {
+        return Err(invalid("missing signature"))
+    }
+    let command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await?;
+    if len as usize > V2_MAX_LEN {
+        return Err(invalid("v2 header is too long"))
+    }
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body).await?;
+    parse_v2(command, family, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+        read_header(&mut bytes).await
+    }
+
+    #[tokio::test]
+    async fn headers_read_back_as_written() {
+        let v4: (SocketAddr, SocketAddr) = ("192.0.2.1:5000".parse().unwrap(), "198.51.100.2:80".parse().unwrap());
+        let v6: (SocketAddr, SocketAddr) = ("[2001:db8::1]:5000".parse().unwrap(), "[2001:db8::2]:80".parse().unwrap());
+        for version in [Version::V1, Version::V2] {
+            for (src, dst) in [v4, v6] {
+                assert_eq!(read(&header(version, src, dst)).await.unwrap(), Some((src, dst)));
+            }
+            assert_eq!(read(&header_unknown(version)).await.unwrap(), None);
+        }
+        assert_eq!(header(Version::V1, v4.0, v4.1), b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 80\r\n");
+    }
+
+    #[tokio::test]
+    async fn mixed_families_are_sent_as_v6() {
+        let src: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:80".parse().unwrap();
+        let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped()), 5000);
+        assert_eq!(read(&header(Version::V2, src, dst)).await.unwrap(), Some((mapped, dst)));
+    }
+
+    #[tokio::test]
+    async fn nothing_past_the_header_is_consumed() {
+        let mut bytes = header(Version::V2, "192.0.2.1:5000".parse().unwrap(), "198.51.100.2:80".parse().unwrap());
+        bytes.extend(b"GET /");
+        let mut reader = &bytes[..];
+        read_header(&mut reader).await.unwrap();
+        assert_eq!(reader, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn truncated_and_oversized_headers_fail() {
+        let v2 = header(Version::V2, "192.0.2.1:5000".parse().unwrap(), "198.51.100.2:80".parse().unwrap());
+        for len in [3, 12, 15, v2.len() - 1] {
+            assert_eq!(read(&v2[..len]).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+        assert_eq!(read(b"PROXY TCP4 192.0.2.1").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut oversized = Vec::from(&V2_SIGNATURE[..]);
+        oversized.extend(&[0x21, 0x11]);
+        oversized.extend(&u16::MAX.to_be_bytes());
+        oversized.resize(oversized.len() + u16::MAX as usize, 0);
+        assert_eq!(read(&oversized).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let long_line = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        assert_eq!(read(long_line.as_bytes()).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(b"HELLO world, no header here").await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
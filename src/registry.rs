@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tokio::io::AsyncWrite;
+use tokio::sync::Notify;
+
+/// Names of the rewrite rules that can be toggled at runtime.
+pub const RULES: [&str; 4] = ["x-frame-options", "auto-login", "password", "constants"];
+
+/// How many recent events are kept for the tui.
+const EVENTS_KEPT: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// A connection to the remote is established, the text is `peer -> backend`.
+    Opened,
+    /// A connection is done, the text has its byte counts.
+    Closed,
+    /// A search pattern is found, the text has where and the bytes around it.
+    SearchHit,
+    /// The search patterns matched the connection as a whole, the text names
+    /// the ones found.
+    Matched,
+    /// The client sent an http request, the text is its request line.
+    Request,
+}
+
+/// Something worth showing that happened on a connection.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub at: chrono::DateTime<chrono::Local>,
+    /// Id of the connection, see `Connection::id`.
+    pub conn: u64,
+    pub kind: EventKind,
+    pub text: String,
+}
+
+/// Called with every event as it happens.
+pub type EventListener = dyn Fn(&Event) + Send + Sync;
+
+struct Listener(Arc<EventListener>);
+
+impl std::fmt::Debug for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Listener")
+    }
+}
+
+/// A connection currently handled by `process_conn`.
+#[derive(Debug)]
+pub struct Connection {
+    pub(super) id: u64,
+    pub(super) peer: SocketAddr,
+    pub(super) local_port: u16,
+    pub(super) backend: Arc<String>,
+    pub(super) started: Instant,
+    pub(super) bytes_out: AtomicU64,
+    pub(super) bytes_in: AtomicU64,
+    blocking_mode: bool,
+    blocking: AtomicBool,
+    matched: AtomicBool,
+    capture: OnceLock<String>,
+    kill: Notify,
+}
+
+impl Connection {
+    /// Unique for the lifetime of the registry, starting at 1.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The client, as far as it is known.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The local port towards the remote, 0 when there is none.
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// `host:port` or `unix:<path>` of the remote.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Bytes written to the remote so far.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written back to the client so far.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// `None` when blocking mode is off and no search action blocked the
+    /// connection, otherwise whether it is being swallowed.
+    pub(super) fn blocking(&self) -> Option<bool> {
+        let blocking = self.blocking.load(Ordering::Relaxed);
+        if self.blocking_mode || blocking {
+            Some(blocking)
+        } else {
+            None
+        }
+    }
+
+    /// Swallow what the client sends from now on, returning whether it was not before.
+    pub(super) fn set_blocking(&self) -> bool {
+        !self.blocking.swap(true, Ordering::Relaxed)
+    }
+
+    /// Whether the search patterns matched this connection.
+    pub fn matched(&self) -> bool {
+        self.matched.load(Ordering::Relaxed)
+    }
+
+    /// Mark the connection matched, returning whether it was not before.
+    pub(super) fn set_matched(&self) -> bool {
+        !self.matched.swap(true, Ordering::Relaxed)
+    }
+
+    /// The name of the capture files once a search action started a capture.
+    pub fn capture(&self) -> Option<&str> {
+        self.capture.get().map(String::as_str)
+    }
+
+    /// Start a capture, returning whether it was not started before.
+    pub(super) fn start_capture(&self) -> bool {
+        let date = chrono::Local::now();
+        self.capture.set(format!("{}-{}", date.format("%Y%m%d-%H%M%S"), self.id)).is_ok()
+    }
+
+    /// Close the connection.
+    pub fn kill(&self) {
+        self.kill.notify_one()
+    }
+
+    pub(super) async fn killed(&self) {
+        self.kill.notified().await
+    }
+}
+
+/// Shared state the admin interface inspects and changes.
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    next_backend: AtomicUsize,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    backends: Mutex<Vec<String>>,
+    disabled_rules: Mutex<BTreeSet<String>>,
+    events: Mutex<VecDeque<Event>>,
+    listener: Mutex<Option<Listener>>,
+}
+
+impl Registry {
+    /// A registry picking from `backends`, each `host:port` or `unix:<path>`.
+    pub fn new(backends: Vec<String>) -> Self {
+        Self {
+            backends: Mutex::new(backends),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn register(&self, peer: SocketAddr, local_port: u16, backend: Arc<String>, blocking_mode: bool) -> Arc<Connection> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let conn = Arc::new(Connection {
+            id,
+            peer,
+            local_port,
+            backend,
+            started: Instant::now(),
+            bytes_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            blocking_mode,
+            blocking: AtomicBool::new(false),
+            matched: AtomicBool::new(false),
+            capture: OnceLock::new(),
+            kill: Notify::new(),
+        });
+        self.connections.lock().unwrap().insert(id, conn.clone());
+        conn
+    }
+
+    pub(super) fn unregister(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    pub fn connections(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn connection(&self, id: u64) -> Option<Arc<Connection>> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Pick the next backend in round robin order.
+    pub fn next_backend(&self) -> Option<String> {
+        let backends = self.backends.lock().unwrap();
+        if backends.is_empty() {
+            return None
+        }
+        let idx = self.next_backend.fetch_add(1, Ordering::Relaxed) % backends.len();
+        Some(backends[idx].clone())
+    }
+
+    pub fn backends(&self) -> Vec<String> {
+        self.backends.lock().unwrap().clone()
+    }
+
+    /// Returns false when the backend is already there.
+    pub fn add_backend(&self, backend: &str) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        if backends.iter().any(|x| x == backend) {
+            return false
+        }
+        backends.push(backend.to_owned());
+        true
+    }
+
+    /// Returns false when there is no such backend.
+    pub fn remove_backend(&self, backend: &str) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        let len = backends.len();
+        backends.retain(|x| x != backend);
+        backends.len() != len
+    }
+
+    /// Call `listener` with every event from now on, replacing the previous one.
+    pub fn set_listener<F: Fn(&Event) + Send + Sync + 'static>(&self, listener: F) {
+        *self.listener.lock().unwrap() = Some(Listener(Arc::new(listener)));
+    }
+
+    pub(super) fn record(&self, kind: EventKind, conn: u64, text: String) {
+        let event = Event { at: chrono::Local::now(), conn, kind, text };
+        let listener = self.listener.lock().unwrap().as_ref().map(|x| x.0.clone());
+        if let Some(listener) = listener {
+            listener(&event);
+        }
+        // every connection opens and closes, keeping those would push out what the tui shows
+        if kind == EventKind::Opened || kind == EventKind::Closed {
+            return
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() == EVENTS_KEPT {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// The most recent events of a kind, newest first.
+    pub fn recent(&self, kind: EventKind, count: usize) -> Vec<Event> {
+        self.events.lock().unwrap().iter().rev().filter(|x| x.kind == kind).take(count).cloned().collect()
+    }
+
+    pub fn rule_enabled(&self, rule: &str) -> bool {
+        !self.disabled_rules.lock().unwrap().contains(rule)
+    }
+
+    /// Turn one of `RULES` on or off, returns false for an unknown rule.
+    pub fn set_rule(&self, rule: &str, enabled: bool) -> bool {
+        if !RULES.contains(&rule) {
+            return false
+        }
+        let mut disabled = self.disabled_rules.lock().unwrap();
+        if enabled {
+            disabled.remove(rule);
+        } else {
+            disabled.insert(rule.to_owned());
+        }
+        true
+    }
+}
+
+/// A writer that adds everything written through it to the counters of its
+/// connection and to `total`, the one of the metrics.
+#[derive(Debug)]
+pub(super) struct Counted<W> {
+    inner: W,
+    conn: Arc<Connection>,
+    outbound: bool,
+    total: Arc<AtomicU64>,
+}
+
+impl<W> Counted<W> {
+    /// `outbound` counts towards `bytes_out` (client to remote), otherwise `bytes_in`.
+    pub(super) fn new(inner: W, conn: Arc<Connection>, outbound: bool, total: Arc<AtomicU64>) -> Self {
+        Self { inner, conn, outbound, total }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        let poll = Pin::new(&mut me.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            let counter = if me.outbound { &me.conn.bytes_out } else { &me.conn.bytes_in };
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+            me.total.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,392 @@
+//! Filters written in Rhai and loaded with --script.
+//!
+//! A script defines any of these functions, all of them get a `conn` map with
+//! the `id`, `peer`, `local_port`, `backend` and `direction` ("out" from the
+//! client, "in" from the remote) of the connection:
+//!
+//! * `on_chunk(conn, data)` with every chunk as a blob;
+//! * `on_http(conn, msg)` with every http message as a map of `start_line`,
+//!   `headers` (an array of `[name, value]`) and `body` (a blob), the chunks
+//!   are buffered until a whole message is there. Messages with a body over
+//!   `MAX_BODY` or without a length go through `on_chunk` instead;
+//! * `on_eof(conn)` once the sending side is done.
+//!
+//! `on_chunk` and `on_http` return `()` to pass the data on unchanged, a new
+//! blob or message to replace it, or `false` to drop it. `on_eof` may return a
+//! blob to inject before the end of the stream. `this` is a map kept for the
+//! connection and direction, and `log(text)` prints a line.
+//!
+//! The file is read again for the next connection whenever it changes.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use kmp::kmp_find;
+use rhai::{Array, Blob, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+
+use crate::filter::StreamFilter;
+use crate::metrics::Direction;
+use crate::registry::Connection;
+
+/// A script is cut off after this many operations per call, a loop in it must
+/// not hang the connection.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Messages whose head does not end within this many bytes are passed on
+/// as chunks.
+const MAX_HEAD: usize = 65536;
+
+/// Larger bodies are passed on as chunks instead of being buffered.
+const MAX_BODY: usize = 16 << 20;
+
+/// The methods of the requests a connection is waiting for answers to, its
+/// two directions share them to know which responses have no body.
+type Methods = Arc<Mutex<VecDeque<String>>>;
+
+/// A script file, compiled again whenever it changes on disk.
+pub(super) struct Script {
+    path: String,
+    engine: Engine,
+    compiled: Mutex<(Option<SystemTime>, Arc<AST>)>,
+    /// Handed from the first filter made for a connection to the second.
+    methods: Mutex<HashMap<u64, Methods>>,
+}
+
+impl Script {
+    pub(super) fn load(path: &str) -> io::Result<Arc<Self>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        // the same nesting limits in debug builds as in release ones
+        engine.set_max_expr_depths(64, 32);
+        let name = path.to_owned();
+        engine.register_fn("log", move |text: &str| {
+            let date = chrono::Local::now();
+            report!("[{}] {}: {}", date.format("%m-%d %H:%M"), name, text);
+        });
+        let modified = std::fs::metadata(path).and_then(|x| x.modified()).ok();
+        let ast = engine.compile_file(path.into())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+        Ok(Arc::new(Self { path: path.to_owned(), engine, compiled: Mutex::new((modified, Arc::new(ast))), methods: Mutex::default() }))
+    }
+
+    /// The latest version of the script that compiles.
+    fn current(&self) -> Arc<AST> {
+        let mut compiled = self.compiled.lock().unwrap();
+        let modified = std::fs::metadata(&self.path).and_then(|x| x.modified()).ok();
+        if modified.is_some() && modified != compiled.0 {
+            compiled.0 = modified;
+            match self.engine.compile_file(self.path.as_str().into()) {
+                Ok(ast) => {
+                    let date = chrono::Local::now();
+                    report!("[{}] script {} is reloaded", date.format("%m-%d %H:%M"), self.path);
+                    compiled.1 = Arc::new(ast);
+                }
+                Err(e) => report!("script {} error, keeping the previous version: {:?}", self.path, e.to_string()),
+            }
+        }
+        compiled.1.clone()
+    }
+
+    /// The filter of one direction for a new connection.
+    pub(super) fn filter(self: &Arc<Self>, conn: &Connection, direction: Direction) -> Box<dyn StreamFilter> {
+        let ast = self.current();
+        let defines = |name: &str| ast.iter_functions().any(|x| x.name == name);
+        let mut meta = Map::new();
+        meta.insert("id".into(), (conn.id() as i64).into());
+        meta.insert("peer".into(), conn.peer().to_string().into());
+        meta.insert("local_port".into(), (conn.local_port() as i64).into());
+        meta.insert("backend".into(), conn.backend().into());
+        meta.insert("direction".into(), match direction { Direction::Out => "out", Direction::In => "in" }.into());
+        let methods = if defines("on_http") {
+            let mut pending = self.methods.lock().unwrap();
+            match pending.remove(&conn.id()) {
+                Some(methods) => methods,
+                None => pending.entry(conn.id()).or_default().clone(),
+            }
+        } else {
+            Methods::default()
+        };
+        Box::new(ScriptFilter {
+            on_chunk: defines("on_chunk"),
+            http: if defines("on_http") { Some(Http::Head) } else { None },
+            on_eof: defines("on_eof"),
+            script: self.clone(),
+            ast,
+            meta: meta.into(),
+            this: Map::new().into(),
+            pending: Vec::new(),
+            methods,
+        })
+    }
+}
+
+/// Where the http parser of a direction is.
+enum Http {
+    /// Waiting for the end of a head.
+    Head,
+    /// Waiting for the rest of the body of a parsed head, this many bytes.
+    Body(Map, usize),
+    /// Passing this many more bytes of a body too large to buffer on.
+    Pass(usize),
+    /// Lost track of the messages, the rest goes through `on_chunk`.
+    Raw,
+}
+
+struct ScriptFilter {
+    script: Arc<Script>,
+    ast: Arc<AST>,
+    meta: Dynamic,
+    this: Dynamic,
+    on_chunk: bool,
+    on_eof: bool,
+    http: Option<Http>,
+    pending: Vec<u8>,
+    methods: Methods,
+}
+
+impl ScriptFilter {
+    fn call(&mut self, name: &str, args: impl rhai::FuncArgs) -> Option<Dynamic> {
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.this);
+        match self.script.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, args) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                report!("script {} {} error: {:?}", self.script.path, name, e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Run raw bytes through `on_chunk`.
+    fn chunk(&mut self, data: Vec<u8>) -> Vec<u8> {
+        if !self.on_chunk || data.is_empty() {
+            return data
+        }
+        let meta = self.meta.clone();
+        let result = self.call("on_chunk", (meta, Dynamic::from_blob(data.clone())));
+        match result {
+            Some(x) if x.is_blob() => x.cast::<Blob>(),
+            Some(x) if x.as_bool() == Ok(false) => Vec::new(),
+            _ => data,
+        }
+    }
+
+    /// Run a whole http message through `on_http`.
+    fn message(&mut self, mut msg: Map, body: Vec<u8>) -> Vec<u8> {
+        let meta = self.meta.clone();
+        msg.insert("body".into(), Dynamic::from_blob(body.clone()));
+        let result = self.call("on_http", (meta, msg.clone()));
+        match result {
+            Some(x) if x.is_map() => serialize(x.cast::<Map>(), &body),
+            Some(x) if x.as_bool() == Ok(false) => Vec::new(),
+            _ => serialize(msg, &body),
+        }
+    }
+
+    /// Cut the pending bytes into http messages, returning what is ready to go on.
+    fn parse(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            match self.http.take() {
+                Some(Http::Head) => match kmp_find(b"\r\n\r\n", &self.pending) {
+                    Some(idx) => {
+                        let rest = self.pending.split_off(idx + 4);
+                        let head = std::mem::replace(&mut self.pending, rest);
+                        match parse_head(&head, &self.methods) {
+                            Some((msg, Some(length))) if length <= MAX_BODY => self.http = Some(Http::Body(msg, length)),
+                            Some((_, Some(length))) => {
+                                out.extend(self.chunk(head));
+                                self.http = Some(Http::Pass(length));
+                            }
+                            // without a length the body runs until the end of the stream
+                            Some((_, None)) | None => {
+                                out.extend(self.chunk(head));
+                                self.http = Some(Http::Raw);
+                            }
+                        }
+                    }
+                    None if self.pending.len() > MAX_HEAD => self.http = Some(Http::Raw),
+                    None => {
+                        self.http = Some(Http::Head);
+                        return out
+                    }
+                },
+                Some(Http::Body(msg, length)) if self.pending.len() >= length => {
+                    let rest = self.pending.split_off(length);
+                    let body = std::mem::replace(&mut self.pending, rest);
+                    out.extend(self.message(msg, body));
+                    self.http = Some(Http::Head);
+                }
+                Some(Http::Pass(length)) => {
+                    let n = length.min(self.pending.len());
+                    let rest = self.pending.split_off(n);
+                    let data = std::mem::replace(&mut self.pending, rest);
+                    out.extend(self.chunk(data));
+                    if n < length {
+                        self.http = Some(Http::Pass(length - n));
+                        return out
+                    }
+                    self.http = Some(Http::Head);
+                }
+                Some(Http::Raw) => {
+                    let data = std::mem::take(&mut self.pending);
+                    out.extend(self.chunk(data));
+                    self.http = Some(Http::Raw);
+                    return out
+                }
+                other => {
+                    self.http = other;
+                    return out
+                }
+            }
+        }
+    }
+}
+
+impl StreamFilter for ScriptFilter {
+    fn on_chunk(&mut self, _conn: &Connection, chunk: &mut Vec<u8>) {
+        let data = std::mem::take(chunk);
+        *chunk = match self.http {
+            Some(_) => {
+                self.pending.extend(data);
+                self.parse()
+            }
+            None => self.chunk(data),
+        };
+    }
+
+    fn on_eof(&mut self, _conn: &Connection) -> Vec<u8> {
+        // a message cut off by the end of the stream goes on as it is
+        let mut out = std::mem::take(&mut self.pending);
+        if let Some(Http::Body(msg, _)) = self.http.take() {
+            let mut head = serialize(msg, &[]);
+            head.extend(out);
+            out = head;
+        }
+        let mut out = self.chunk(out);
+        if self.on_eof {
+            let meta = self.meta.clone();
+            if let Some(x) = self.call("on_eof", (meta,)) {
+                if x.is_blob() {
+                    out.extend(x.cast::<Blob>());
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The start line and headers of an http message, and the length of its body
+/// when it is known. Requests note their method in `methods`, responses take
+/// the one they answer from it.
+fn parse_head(head: &[u8], methods: &Methods) -> Option<(Map, Option<usize>)> {
+    let text = std::str::from_utf8(head).ok()?;
+    let mut lines = text.trim_end_matches("\r\n").split("\r\n");
+    let start_line = lines.next()?;
+    let request = !start_line.starts_with("HTTP/");
+    if !request && !start_line.contains(' ') {
+        return None
+    }
+    let status = start_line.split(' ').nth(1).unwrap_or("");
+    let mut answers_head = false;
+    if request {
+        methods.lock().unwrap().push_back(start_line.split(' ').next().unwrap_or("").to_owned());
+    } else if !status.starts_with('1') {
+        // interim responses come before the real one
+        answers_head = methods.lock().unwrap().pop_front().is_some_and(|x| x == "HEAD");
+    }
+    let mut headers = Array::new();
+    let mut length = None;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse().ok()?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = true;
+        }
+        headers.push(Dynamic::from_array(vec![name.into(), value.into()]));
+    }
+    // only responses may go on until the connection is closed
+    let bodyless = !request && (answers_head || status.starts_with('1') || status == "204" || status == "304");
+    let length = match (length, chunked) {
+        _ if bodyless => Some(0),
+        (_, true) => None,
+        (Some(length), false) => Some(length),
+        (None, false) if request || bodyless => Some(0),
+        (None, false) => None,
+    };
+    let mut msg = Map::new();
+    msg.insert("start_line".into(), start_line.into());
+    msg.insert("headers".into(), headers.into());
+    msg.insert("body".into(), Dynamic::from_blob(Vec::new()));
+    Some((msg, length))
+}
+
+/// Write a message from a script back out. When its body is no longer
+/// `original` the content-length is set to match it, or added.
+fn serialize(msg: Map, original: &[u8]) -> Vec<u8> {
+    let body = msg.get("body").filter(|x| x.is_blob()).map(|x| x.clone().cast::<Blob>()).unwrap_or_default();
+    let changed = body != original;
+    let mut length_written = false;
+    let mut out = msg.get("start_line").map(|x| x.to_string()).unwrap_or_default().into_bytes();
+    out.extend(b"\r\n");
+    let headers = msg.get("headers").filter(|x| x.is_array()).map(|x| x.clone().cast::<Array>()).unwrap_or_default();
+    for header in headers {
+        let header = match header.try_cast::<Array>() {
+            Some(x) if x.len() == 2 => x,
+            _ => continue,
+        };
+        let name = header[0].to_string();
+        let value = if changed && name.eq_ignore_ascii_case("content-length") {
+            length_written = true;
+            body.len().to_string()
+        } else {
+            header[1].to_string()
+        };
+        out.extend(format!("{}: {}\r\n", name, value).into_bytes());
+    }
+    if changed && !length_written {
+        out.extend(format!("Content-Length: {}\r\n", body.len()).into_bytes());
+    }
+    out.extend(b"\r\n");
+    out.extend(body);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_to_head_requests_have_no_body() {
+        let methods = Methods::default();
+        parse_head(b"HEAD /big HTTP/1.1\r\n\r\n", &methods).unwrap();
+        parse_head(b"GET /big HTTP/1.1\r\n\r\n", &methods).unwrap();
+        let (_, length) = parse_head(b"HTTP/1.1 100 Continue\r\n\r\n", &methods).unwrap();
+        assert_eq!(length, Some(0));
+        let (_, length) = parse_head(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", &methods).unwrap();
+        assert_eq!(length, Some(0));
+        let (_, length) = parse_head(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", &methods).unwrap();
+        assert_eq!(length, Some(100));
+    }
+
+    #[test]
+    fn a_changed_body_gets_its_length() {
+        let methods = Methods::default();
+        let (mut msg, _) = parse_head(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n", &methods).unwrap();
+        msg.insert("body".into(), Dynamic::from_blob(b"hello".to_vec()));
+        assert_eq!(serialize(msg, b"hi"), b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+
+        // the answer to a HEAD request keeps the length of what GET would give
+        parse_head(b"HEAD / HTTP/1.1\r\n\r\n", &methods).unwrap();
+        let (msg, _) = parse_head(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", &methods).unwrap();
+        assert_eq!(serialize(msg, b""), b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+
+        let (mut msg, _) = parse_head(b"POST / HTTP/1.1\r\nHost: a\r\n\r\n", &methods).unwrap();
+        msg.insert("body".into(), Dynamic::from_blob(b"x=1".to_vec()));
+        assert_eq!(serialize(msg, b""), b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nx=1");
+    }
+}
@@ -0,0 +1,372 @@
+//! The search patterns of --search and --search-regex, and the filter that
+//! logs and records where they match.
+//!
+//! A pattern may start with `out:` (what the client sends), `in:` (what the
+//! remote sends back) or `both:`. Without one it searches where --search
+//! always did: what the client sends, and the remote's responses too with
+//! --password or a login profile.
+//!
+//! Every match is logged, also one that spans chunks. A connection is marked
+//! matched once all patterns are found in it, in either direction and within
+//! --search-window bytes of traffic when that is given, or once any is found
+//! with --pattern-or.
+//!
+//! --search-action runs actions on every hit of a pattern, see `action`.
+
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::action::{self, Action, Capture, Webhook};
+use crate::admin::json_string;
+use crate::filter::StreamFilter;
+use crate::metrics::{Direction, Metrics};
+use crate::options::Options;
+use crate::registry::{Connection, EventKind, Registry};
+
+/// Bytes shown before and after a match when --context is not given.
+const CONTEXT: usize = 64;
+
+/// Where --capture-dir puts the captures when it is not given.
+const CAPTURE_DIR: &str = "captures";
+
+/// Bytes kept from the previous chunks of a direction, a match may span
+/// chunks as long as it is not longer than this.
+const OVERLAP: usize = 4096;
+
+/// How search hits are logged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HitFormat {
+    /// The bytes as text, with anything that is not utf-8 replaced.
+    Text,
+    /// The bytes in hex after their range in the stream.
+    Hex,
+    /// A json object per line with the bytes in base64.
+    Json,
+}
+
+impl FromStr for HitFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(HitFormat::Text),
+            "hex" => Ok(HitFormat::Hex),
+            "json" => Ok(HitFormat::Json),
+            _ => Err(format!("unknown hit format {:?}, expected text, hex or json", s)),
+        }
+    }
+}
+
+/// Which directions a pattern searches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scope {
+    Default,
+    Only(Direction),
+    Both,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Pattern {
+    /// The pattern as given, it names the pattern in the log and the metrics.
+    text: String,
+    scope: Scope,
+    regex: Regex,
+    actions: Vec<Action>,
+}
+
+impl Pattern {
+    fn parse(text: &str, literal: bool, ignore_case: bool) -> io::Result<Self> {
+        let (scope, body) = match text.split_once(':') {
+            Some(("out", body)) => (Scope::Only(Direction::Out), body),
+            Some(("in", body)) => (Scope::Only(Direction::In), body),
+            Some(("both", body)) => (Scope::Both, body),
+            _ => (Scope::Default, text),
+        };
+        let body = if literal { regex::escape(body) } else { body.to_owned() };
+        let regex = RegexBuilder::new(&body)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("search pattern {:?}: {}", text, e)))?;
+        Ok(Self { text: text.to_owned(), scope, regex, actions: Vec::new() })
+    }
+
+    fn searches(&self, direction: Direction, login: bool) -> bool {
+        match self.scope {
+            Scope::Default => direction == Direction::Out || login,
+            Scope::Only(x) => x == direction,
+            Scope::Both => true,
+        }
+    }
+}
+
+/// Every search pattern and how their matches are combined and shown.
+#[derive(Debug, Default)]
+pub(super) struct Patterns {
+    list: Vec<Pattern>,
+    /// A connection matches when any pattern is found, rather than all of them.
+    any: bool,
+    /// All patterns have to be found within this many bytes of traffic.
+    window: Option<u64>,
+    context: Option<usize>,
+    format: Option<HitFormat>,
+    webhook: Option<Arc<Webhook>>,
+    capture_dir: Arc<PathBuf>,
+}
+
+impl Patterns {
+    pub(super) fn from_options(options: &Options) -> io::Result<Self> {
+        let mut list = Vec::new();
+        for text in &options.search {
+            list.push(Pattern::parse(text, true, options.ignore_case)?);
+        }
+        for text in &options.search_regex {
+            list.push(Pattern::parse(text, false, options.ignore_case)?);
+        }
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        for spec in &options.search_action {
+            let (text, actions) = action::parse(spec).map_err(invalid)?;
+            let pattern = list.iter_mut().find(|x| x.text == text)
+                .ok_or_else(|| invalid(format!("search action {:?} is for no --search or --search-regex pattern", spec)))?;
+            if actions.contains(&Action::Webhook) && options.webhook.is_none() {
+                return Err(invalid(format!("search action {:?} needs --webhook", spec)))
+            }
+            pattern.actions.extend(actions);
+        }
+        Ok(Self {
+            list,
+            any: options.pattern_or,
+            window: options.search_window,
+            context: options.context,
+            format: options.hit_format,
+            webhook: options.webhook.clone().map(Arc::new),
+            capture_dir: Arc::new(options.capture_dir.clone().unwrap_or_else(|| PathBuf::from(CAPTURE_DIR))),
+        })
+    }
+
+    /// Literal patterns, which always compile.
+    pub(super) fn literal(patterns: &[String], any: bool) -> Self {
+        let list = patterns.iter().filter_map(|x| Pattern::parse(x, true, false).ok()).collect();
+        Self { list, any, ..Self::default() }
+    }
+
+    /// Whether any pattern searches `direction`.
+    pub(super) fn searches(&self, direction: Direction, login: bool) -> bool {
+        self.list.iter().any(|x| x.searches(direction, login))
+    }
+
+    fn acts(&self, action: Action) -> bool {
+        self.list.iter().any(|x| x.actions.contains(&action))
+    }
+
+    /// Whether a search action may block connections.
+    pub(super) fn blocks(&self) -> bool {
+        self.acts(Action::Block)
+    }
+
+    /// The filter capturing a direction once a search action says so, `None`
+    /// without any capture action.
+    pub(super) fn capture(&self, direction: Direction) -> Option<Capture> {
+        if self.acts(Action::Capture) {
+            Some(Capture::new(self.capture_dir.clone(), direction))
+        } else {
+            None
+        }
+    }
+
+    /// A fresh record of what a connection found.
+    pub(super) fn matches(&self) -> Arc<Mutex<Matches>> {
+        Arc::new(Mutex::new(Matches {
+            names: self.list.iter().map(|x| x.text.clone()).collect(),
+            traffic: 0,
+            seen: vec![None; self.list.len()],
+        }))
+    }
+}
+
+/// What the search filters of a connection found so far, shared by both
+/// directions.
+pub(super) struct Matches {
+    names: Vec<String>,
+    /// Bytes searched in both directions.
+    traffic: u64,
+    /// Where in the traffic each pattern was last found.
+    seen: Vec<Option<u64>>,
+}
+
+impl Matches {
+    fn complete(&self, any: bool, window: Option<u64>) -> bool {
+        if any {
+            return self.seen.iter().any(Option::is_some)
+        }
+        match self.seen.iter().copied().collect::<Option<Vec<u64>>>() {
+            Some(seen) => window.is_none_or(|x| self.traffic - seen.into_iter().min().unwrap_or(0) <= x),
+            None => false,
+        }
+    }
+}
+
+/// Logs and records where the search patterns of its direction are found,
+/// and marks the connection matched once all of them, or any of them in
+/// `--pattern-or` mode, are found.
+pub(super) struct Search {
+    direction: Direction,
+    /// The patterns of this direction with their index in the `Patterns`.
+    patterns: Vec<(usize, Pattern)>,
+    any: bool,
+    window: Option<u64>,
+    /// Without any pattern at all every chunk is logged.
+    everything: bool,
+    context: usize,
+    format: HitFormat,
+    webhook: Option<Arc<Webhook>>,
+    capture_dir: Arc<PathBuf>,
+    /// Where the next chunk starts in the stream.
+    pos: usize,
+    /// The end of the previous chunks, for matches that span chunks.
+    tail: Vec<u8>,
+    /// Where the last logged match of each pattern ends in the stream, a
+    /// match that only grew with the new bytes (`\d+`) is not logged again.
+    ends: Vec<usize>,
+    matches: Arc<Mutex<Matches>>,
+    metrics: Arc<Metrics>,
+    registry: Arc<Registry>,
+}
+
+impl Search {
+    pub(super) fn new(client: &crate::Client, direction: Direction, login: bool, matches: Arc<Mutex<Matches>>) -> Self {
+        let patterns: Vec<_> = client.search.list.iter().cloned().enumerate().filter(|(_, x)| x.searches(direction, login)).collect();
+        let ends = vec![0; patterns.len()];
+        Self {
+            direction,
+            patterns,
+            any: client.search.any,
+            window: client.search.window,
+            everything: client.search.list.is_empty(),
+            context: client.search.context.unwrap_or(CONTEXT),
+            format: client.search.format.unwrap_or(HitFormat::Text),
+            webhook: client.search.webhook.clone(),
+            capture_dir: client.search.capture_dir.clone(),
+            pos: 0,
+            tail: Vec::new(),
+            ends,
+            matches,
+            metrics: client.metrics.clone(),
+            registry: client.registry.clone(),
+        }
+    }
+
+    fn direction(&self) -> &'static str {
+        match self.direction { Direction::Out => "out", Direction::In => "in" }
+    }
+
+    /// Log `data`, which is at `start` in the stream, for a hit of `pattern`
+    /// at `offset`.
+    fn show(&self, conn: &Connection, pattern: Option<&Pattern>, offset: usize, data: &[u8], start: usize) {
+        let date = chrono::Local::now();
+        let direction = self.direction();
+        let name = pattern.map_or_else(String::new, |x| format!(" {:?}", x.text));
+        match (self.format, pattern) {
+            // every chunk is logged as it is without patterns
+            (HitFormat::Text, None) => report!("{}", String::from_utf8_lossy(data)),
+            (HitFormat::Text, Some(_)) => report!("[{}] #{} {} @{}{}: {}", date.format("%m-%d %H:%M"), conn.id, direction, offset, name, String::from_utf8_lossy(data)),
+            (HitFormat::Hex, _) => {
+                let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                report!("[{}] #{} {} @{}{}: {}..{}:{}", date.format("%m-%d %H:%M"), conn.id, direction, offset, name, start, start + data.len(), hex)
+            }
+            (HitFormat::Json, _) => report!("{}", self.json(conn, pattern, offset, data, start)),
+        }
+    }
+
+    /// A hit as a json object, with the bytes in base64.
+    fn json(&self, conn: &Connection, pattern: Option<&Pattern>, offset: usize, data: &[u8], start: usize) -> String {
+        format!(
+            "{{\"time\":{},\"conn\":{},\"direction\":\"{}\",\"offset\":{},\"pattern\":{},\"start\":{},\"data\":\"{}\"}}",
+            json_string(&chrono::Local::now().to_rfc3339()),
+            conn.id,
+            self.direction(),
+            offset,
+            pattern.map_or_else(|| String::from("null"), |x| json_string(&x.text)),
+            start,
+            base64::engine::general_purpose::STANDARD.encode(data),
+        )
+    }
+
+    /// Run the actions of `pattern` for a hit.
+    fn act(&self, conn: &Connection, pattern: &Pattern, offset: usize, data: &[u8], start: usize) {
+        let date = chrono::Local::now();
+        for action in &pattern.actions {
+            match action {
+                Action::Capture => if conn.start_capture() {
+                    let name = conn.capture().unwrap_or_default();
+                    report!("[{}] #{} is captured to {}-*.bin", date.format("%m-%d %H:%M"), conn.id, self.capture_dir.join(name).display());
+                },
+                Action::Webhook => if let Some(webhook) = &self.webhook {
+                    webhook.send(self.json(conn, Some(pattern), offset, data, start));
+                },
+                Action::Kill => {
+                    report!("[{}] #{} is closed for {:?}", date.format("%m-%d %H:%M"), conn.id, pattern.text);
+                    conn.kill();
+                }
+                Action::Block => if conn.set_blocking() {
+                    report!("[{}] #{} is blocked for {:?}", date.format("%m-%d %H:%M"), conn.id, pattern.text);
+                },
+            }
+        }
+    }
+}
+
+impl StreamFilter for Search {
+    fn on_chunk(&mut self, conn: &Connection, chunk: &mut Vec<u8>) {
+        if self.patterns.is_empty() {
+            if self.everything && !self.any {
+                let text = String::from_utf8_lossy(chunk);
+                self.registry.record(EventKind::SearchHit, conn.id, text.chars().take(200).collect());
+                self.show(conn, None, self.pos, chunk, self.pos);
+            }
+            self.pos += chunk.len();
+            return
+        }
+
+        // search the new bytes together with the end of the previous ones
+        let mut data = std::mem::take(&mut self.tail);
+        let skip = data.len();
+        let start = self.pos - skip;
+        data.extend_from_slice(chunk);
+        let mut found = Vec::new();
+        for (i, (idx, pattern)) in self.patterns.iter().enumerate() {
+            // the matches before the new bytes are logged already
+            let logged = self.ends[i];
+            for m in pattern.regex.find_iter(&data).filter(|x| x.end() > skip && start + x.start() >= logged) {
+                self.ends[i] = start + m.end();
+                found.push(*idx);
+                self.metrics.search_hit(&pattern.text);
+                let around = m.start().saturating_sub(self.context)..(m.end() + self.context).min(data.len());
+                self.show(conn, Some(pattern), start + m.start(), &data[around.clone()], start + around.start);
+                let text = String::from_utf8_lossy(&data[around.clone()]);
+                self.registry.record(EventKind::SearchHit, conn.id, format!("{} @{} {}", self.direction(), start + m.start(), text.chars().take(200).collect::<String>()));
+                self.act(conn, pattern, start + m.start(), &data[around.clone()], start + around.start);
+            }
+        }
+
+        let mut matches = self.matches.lock().unwrap();
+        matches.traffic += chunk.len() as u64;
+        for idx in found {
+            matches.seen[idx] = Some(matches.traffic);
+        }
+        if !conn.matched() && matches.complete(self.any, self.window) && conn.set_matched() {
+            let names: Vec<&str> = matches.names.iter().zip(&matches.seen).filter(|(_, x)| x.is_some()).map(|(x, _)| x.as_str()).collect();
+            let date = chrono::Local::now();
+            report!("[{}] #{} matched {:?}", date.format("%m-%d %H:%M"), conn.id, names);
+            self.registry.record(EventKind::Matched, conn.id, names.join(", "));
+        }
+        drop(matches);
+
+        self.pos += chunk.len();
+        self.tail = data.split_off(data.len().saturating_sub(OVERLAP));
+    }
+}
@@ -0,0 +1,250 @@
+//! Encrypted and authenticated link between two tcpforward instances.
+//!
+//! Both sides share a key given on the command line. The dialing side sends 32
+//! random bytes, the accepting side answers with 32 of its own, and each
+//! direction derives its ChaCha20-Poly1305 key from the shared key and both
+//! randoms with HKDF-SHA256. Then each side sends an empty sealed record, so a
+//! wrong key is noticed before anything is forwarded.
+//!
+//! A record is a 2 byte big endian length followed by that many bytes of
+//! ciphertext and tag. The nonce is a per-direction record counter, so records
+//! can not be replayed or reordered without the link failing. A side that is
+//! done writing sends another empty sealed record; a link that ends without
+//! it was cut short, and reading it fails instead of ending cleanly.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+/// Largest plaintext carried by one record.
+const MAX_RECORD: usize = 16 * 1024;
+
+/// Poly1305 tag appended to every record.
+const TAG_LEN: usize = 16;
+
+/// How long the peer has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn failed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("secure: {}", message))
+}
+
+/// One direction of the link.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(psk: &[u8], salt: &[u8], info: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), psk).expand(info, &mut key).expect("32 bytes is a valid hkdf length");
+        Self { aead: ChaCha20Poly1305::new(Key::from_slice(&key)), counter: 0 }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        *Nonce::from_slice(&nonce)
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(&mut self, writer: &mut W, plaintext: &[u8]) -> io::Result<()> {
+        let nonce = self.nonce();
+        let sealed = self.aead.encrypt(&nonce, plaintext).map_err(|_| failed("encryption failed"))?;
+        let mut record = Vec::with_capacity(2 + sealed.len());
+        record.extend(&(sealed.len() as u16).to_be_bytes());
+        record.extend(sealed);
+        writer.write_all(&record).await?;
+        writer.flush().await
+    }
+
+    async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<Vec<u8>> {
+        let len = reader.read_u16().await? as usize;
+        if !(TAG_LEN..=MAX_RECORD + TAG_LEN).contains(&len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "secure: bad record length"))
+        }
+        let mut sealed = vec![0u8; len];
+        reader.read_exact(&mut sealed).await?;
+        let nonce = self.nonce();
+        self.aead.decrypt(&nonce, sealed.as_slice()).map_err(|_| failed("record does not authenticate, is the key the same on both sides?"))
+    }
+}
+
+async fn handshake<S>(conn: &mut S, psk: &[u8], dialer: bool) -> io::Result<(Cipher, Cipher)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut ours = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut ours);
+    let mut theirs = [0u8; 32];
+    if dialer {
+        conn.write_all(&ours).await?;
+        conn.read_exact(&mut theirs).await?;
+    } else {
+        conn.read_exact(&mut theirs).await?;
+        conn.write_all(&ours).await?;
+    }
+
+    let (dialer_random, acceptor_random) = if dialer { (ours, theirs) } else { (theirs, ours) };
+    let mut salt = dialer_random.to_vec();
+    salt.extend(&acceptor_random);
+    let outbound = Cipher::new(psk, &salt, b"tcpforward dialer to acceptor");
+    let inbound = Cipher::new(psk, &salt, b"tcpforward acceptor to dialer");
+    let (mut sealing, mut opening) = if dialer { (outbound, inbound) } else { (inbound, outbound) };
+
+    sealing.write(conn, &[]).await?;
+    if !opening.read(conn).await?.is_empty() {
+        return Err(failed("unexpected handshake record"))
+    }
+    Ok((sealing, opening))
+}
+
+/// The plaintext side of a link. Once the link failed, reading its end gives
+/// an error rather than a clean end of stream.
+pub(super) struct Link {
+    inner: DuplexStream,
+    failed: Arc<AtomicBool>,
+}
+
+impl AsyncRead for Link {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == before && buf.remaining() > 0 && self.failed.load(Ordering::Acquire) => {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "secure: the link was cut without a close record")))
+            }
+            other => other,
+        }
+    }
+}
+
+impl AsyncWrite for Link {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Run the handshake over `conn` and return a stream whose traffic is
+/// encrypted on `conn`. `dialer` tells which side of the link this is.
+pub(super) async fn wrap<S>(mut conn: S, psk: &[u8], dialer: bool) -> io::Result<Link>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sealing, mut opening) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut conn, psk, dialer)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "secure: handshake timed out"))??;
+
+    let (user, link_side) = tokio::io::duplex(MAX_RECORD);
+    let failed = Arc::new(AtomicBool::new(false));
+    let failing = failed.clone();
+    let (mut plain_reader, mut plain_writer) = tokio::io::split(link_side);
+    let (mut conn_reader, mut conn_writer) = tokio::io::split(conn);
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_RECORD];
+        loop {
+            let n = match plain_reader.read(&mut buf).await {
+                Ok(0) => {
+                    // the close record, data records are never empty
+                    if sealing.write(&mut conn_writer, &[]).await.is_err() {
+                        return
+                    }
+                    break
+                }
+                Ok(n) => n,
+                Err(_) => break,
+            };
+            if sealing.write(&mut conn_writer, &buf[..n]).await.is_err() {
+                return
+            }
+        }
+        let _ = conn_writer.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        loop {
+            match opening.read(&mut conn_reader).await {
+                Ok(plaintext) if plaintext.is_empty() => break,
+                Ok(plaintext) => {
+                    if plain_writer.write_all(&plaintext).await.is_err() {
+                        break
+                    }
+                }
+                Err(e) => {
+                    let e = match e.kind() {
+                        io::ErrorKind::UnexpectedEof => String::from("the link was cut without a close record"),
+                        _ => e.to_string(),
+                    };
+                    report!("secure link error: {:?}", e);
+                    failing.store(true, Ordering::Release);
+                    break
+                }
+            }
+        }
+        let _ = plain_writer.shutdown().await;
+    });
+
+    Ok(Link { inner: user, failed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_closed_link_ends_cleanly() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (dialer, acceptor) = tokio::join!(wrap(a, b"key", true), wrap(b, b"key", false));
+        let (mut dialer, mut acceptor) = (dialer.unwrap(), acceptor.unwrap());
+        dialer.write_all(b"hello").await.unwrap();
+        dialer.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        acceptor.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn a_cut_link_fails_to_read() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (dialer, far) = tokio::join!(wrap(a, b"key", true), async {
+            let mut b = b;
+            // play the acceptor by hand to end the link after a data record
+            let mut theirs = [0u8; 32];
+            b.read_exact(&mut theirs).await.unwrap();
+            let ours = [1u8; 32];
+            b.write_all(&ours).await.unwrap();
+            let mut salt = theirs.to_vec();
+            salt.extend(&ours);
+            let mut sealing = Cipher::new(b"key", &salt, b"tcpforward acceptor to dialer");
+            sealing.write(&mut b, &[]).await.unwrap();
+            sealing.write(&mut b, b"partial").await.unwrap();
+            b
+        });
+        let mut dialer = dialer.unwrap();
+        drop(far);
+
+        let mut received = [0; 7];
+        dialer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"partial");
+        let e = dialer.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,454 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::options::Options;
+use crate::first_bytes::{self, Prefixed};
+use crate::{compress, filter, http_connect, login, metrics, mux, process_conn, proxy_protocol, registry, search, secure, socks5, transparent, tunnel, upstream, Client, Stream};
+
+/// Picks the remote, `host:port` or `unix:<path>`, for a client from its
+/// address and the local address it connected to. `None` drops the client.
+pub type RemoteSelector = dyn Fn(SocketAddr, SocketAddr) -> Option<String> + Send + Sync;
+
+/// Everything the accepted connections share.
+pub(super) struct Service {
+    pub(super) options: Options,
+    pub(super) password: Option<Arc<String>>,
+    pub(super) search: Arc<search::Patterns>,
+    pub(super) rule: Arc<String>,
+    pub(super) metrics: Arc<metrics::Metrics>,
+    pub(super) registry: Arc<registry::Registry>,
+    pub(super) tunnel: Option<Arc<tunnel::TunnelServer>>,
+    pub(super) mux: mux::Dialer,
+    pub(super) selector: Option<Arc<RemoteSelector>>,
+    pub(super) filters: filter::Factories,
+    /// What decides from their first bytes how clients are relayed, with
+    /// --first-bytes or --blocking-mode.
+    pub(super) first_bytes: Option<first_bytes::Rules>,
+    /// The auto-login profiles of --login-profiles.
+    pub(super) profiles: login::Profiles,
+}
+
+impl Service {
+    /// The remote for a client without a destination of its own.
+    fn select_backend(&self, peer_addr: SocketAddr, local_addr: SocketAddr) -> Option<String> {
+        match &self.selector {
+            Some(selector) => selector(peer_addr, local_addr),
+            None => self.registry.next_backend(),
+        }
+    }
+}
+
+/// Judge a client by its first bytes, returning them with the action of the
+/// rule that decided, `None` when no rule did. `None` when the client is gone
+/// before sending anything.
+async fn screen<S: AsyncRead + Unpin>(service: &Service, local: &mut S, peer_addr: SocketAddr) -> Option<(Vec<u8>, Option<first_bytes::Action>)> {
+    let rules = match &service.first_bytes {
+        Some(rules) => rules,
+        None => return Some((Vec::new(), None)),
+    };
+    let (first, verdict) = match rules.sniff(local).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return None,
+        Err(e) => {
+            report!("first bytes of {:?} error: {:?}", peer_addr, e.to_string());
+            return None;
+        }
+    };
+    if let Some((rule, action)) = verdict {
+        if *action != first_bytes::Action::Pass {
+            let date = chrono::Local::now();
+            report!("[{}] {:?} matches {:?}: {}", date.format("%m-%d %H:%M"), peer_addr, rule, action);
+        }
+    }
+    let verdict = verdict.map(|(_, action)| action.clone());
+    Some((first, verdict))
+}
+
+async fn accept_conn(service: Arc<Service>, mut local: TcpStream, mut peer_addr: SocketAddr, listener: SocketAddr) {
+    let options = &service.options;
+    let metrics = &service.metrics;
+
+    let mut local_addr = match local.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            report!("local address of {:?} error: {:?}", peer_addr, e.to_string());
+            return;
+        }
+    };
+    if options.accept_proxy {
+        match tokio::time::timeout(Duration::from_secs(5), proxy_protocol::read_header(&mut local)).await {
+            Ok(Ok(Some((src, dst)))) => {
+                peer_addr = src;
+                local_addr = dst;
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                report!("proxy header from {:?} error: {:?}", peer_addr, e.to_string());
+                return;
+            }
+            Err(_) => {
+                report!("proxy header from {:?} timed out", peer_addr);
+                return;
+            }
+        }
+    }
+    let date = chrono::Local::now();
+    report!("[{}] a new connection {:?} is coming!", date.format("%m-%d %H:%M"), peer_addr);
+    metrics.accepted();
+
+    let (first, verdict) = match screen(&service, &mut local, peer_addr).await {
+        Some(x) => x,
+        None => {
+            metrics.refused();
+            return;
+        }
+    };
+    if verdict == Some(first_bytes::Action::Reset) {
+        // closing with a zero linger sends a reset, and does not block
+        #[allow(deprecated)]
+        let _ = local.set_linger(Some(Duration::ZERO));
+        metrics.refused();
+        return;
+    }
+
+    let backend = if service.tunnel.is_some() {
+        Arc::new(String::from("tunnel"))
+    } else if let Some(first_bytes::Action::Redirect(remote)) = &verdict {
+        Arc::new(remote.clone())
+    } else if options.socks5 {
+        let credentials = options.socks_user.as_deref().zip(options.socks_password.as_deref());
+        match tokio::time::timeout(Duration::from_secs(10), socks5::handshake(&mut local, credentials)).await {
+            Ok(Ok(dst)) => Arc::new(dst),
+            Ok(Err(e)) => {
+                report!("socks5 handshake with {:?} error: {:?}", peer_addr, e.to_string());
+                metrics.refused();
+                return;
+            }
+            Err(_) => {
+                report!("socks5 handshake with {:?} timed out", peer_addr);
+                metrics.refused();
+                return;
+            }
+        }
+    } else if options.http_connect {
+        let credentials = options.proxy_user.as_deref().zip(options.proxy_password.as_deref());
+        match tokio::time::timeout(Duration::from_secs(10), http_connect::handshake(&mut local, &options.allow, credentials)).await {
+            Ok(Ok(dst)) => Arc::new(dst),
+            Ok(Err(e)) => {
+                report!("http connect from {:?} error: {:?}", peer_addr, e.to_string());
+                metrics.refused();
+                return;
+            }
+            Err(_) => {
+                report!("http connect from {:?} timed out", peer_addr);
+                metrics.refused();
+                return;
+            }
+        }
+    } else if options.transparent {
+        match transparent::original_dst(&local, listener) {
+            Ok(dst) => Arc::new(dst.to_string()),
+            Err(e) => {
+                report!("original destination of {:?} error: {:?}", peer_addr, e.to_string());
+                metrics.refused();
+                return;
+            }
+        }
+    } else if options.tproxy {
+        // a TPROXY diverted connection keeps its original destination as local address
+        match transparent::tproxy_dst(local_addr, listener) {
+            Ok(dst) => Arc::new(dst.to_string()),
+            Err(e) => {
+                report!("original destination of {:?} error: {:?}", peer_addr, e.to_string());
+                metrics.refused();
+                return;
+            }
+        }
+    } else {
+        match service.select_backend(peer_addr, local_addr) {
+            Some(backend) => Arc::new(backend),
+            None => {
+                report!("no backend is configured, dropping {:?}", peer_addr);
+                metrics.refused();
+                return;
+            }
+        }
+    };
+    let connect_start = Instant::now();
+    let remote: io::Result<(Box<dyn Stream>, Option<SocketAddr>)> = match &service.tunnel {
+        Some(tunnel) => tunnel.open(peer_addr, local_addr).await.map(|s| (Box::new(s) as Box<dyn Stream>, None)),
+        None if options.mux => {
+            service.mux.open(|| async { dial(options, &backend).await.map(|(s, _)| s) }).await
+                .map(|s| (Box::new(s) as Box<dyn Stream>, None))
+        }
+        None => dial(options, &backend).await,
+    };
+    let remote = match remote {
+        Ok((s, bound)) => compress_remote(options, s).await.map(|(s, stats)| (s, bound, stats)),
+        Err(e) => Err(e),
+    };
+    let (mut remote, bound, compression) = match remote {
+        Ok(s) => s,
+        Err(e) => {
+            report!("connect to remote error: {:?}", e.to_string());
+            metrics.refused();
+            if options.socks5 {
+                let _ = socks5::reply(&mut local, Err(&e)).await;
+            } else if options.http_connect {
+                let _ = http_connect::reply(&mut local, Err(&e)).await;
+            }
+            return;
+        }
+    };
+    if let Some(version) = options.send_proxy {
+        if let Err(e) = remote.write_all(&proxy_protocol::header(version, peer_addr, local_addr)).await {
+            report!("send proxy header error: {:?}", e.to_string());
+            metrics.refused();
+            return;
+        }
+    }
+    metrics.connected(connect_start.elapsed());
+    // a multiplexed stream has no address of its own
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    if options.socks5 {
+        if let Err(e) = socks5::reply(&mut local, Ok(bound)).await {
+            report!("socks5 reply to {:?} error: {:?}", peer_addr, e.to_string());
+            return;
+        }
+    } else if options.http_connect {
+        if let Err(e) = http_connect::reply(&mut local, Ok(())).await {
+            report!("http connect reply to {:?} error: {:?}", peer_addr, e.to_string());
+            return;
+        }
+    }
+
+    forward(&service, Prefixed::new(first, local), remote, peer_addr, backend, bound.port(), compression, verdict).await;
+}
+
+/// Connect to `backend`, `host:port` or `unix:<path>`, encrypting the link when it
+/// leads to another tcpforward running with --secure-local.
+async fn dial(options: &Options, backend: &str) -> io::Result<(Box<dyn Stream>, Option<SocketAddr>)> {
+    let (stream, bound): (Box<dyn Stream>, _) = match backend.strip_prefix("unix:") {
+        Some(path) => (Box::new(UnixStream::connect(path).await?), None),
+        None => {
+            let stream = upstream::connect(backend, options.via.as_ref()).await?;
+            let bound = stream.local_addr().ok();
+            (Box::new(stream), bound)
+        }
+    };
+    match options.psk.as_deref() {
+        Some(psk) if options.secure_remote => {
+            let secured = secure::wrap(stream, psk.as_bytes(), true).await?;
+            Ok((Box::new(secured), bound))
+        }
+        _ => Ok((stream, bound)),
+    }
+}
+
+/// Compress a connection to the remote when it leads to another tcpforward
+/// running with --compress-local.
+async fn compress_remote(options: &Options, remote: Box<dyn Stream>) -> io::Result<(Box<dyn Stream>, Option<Arc<compress::Stats>>)> {
+    match options.compress_remote {
+        Some(algorithm) => {
+            let (stream, stats) = compress::dial(remote, algorithm).await?;
+            Ok((Box::new(stream), Some(stats)))
+        }
+        None => Ok((remote, None)),
+    }
+}
+
+/// Register a connected pair and relay it until either side is done. What
+/// the client sends is swallowed when the `verdict` of the first bytes rules
+/// is to drop it, and blocking mode judges the clients no rule decided about.
+#[allow(clippy::too_many_arguments)]
+async fn forward<L, R>(service: &Service, local: L, remote: R, peer_addr: SocketAddr, backend: Arc<String>, local_port: u16, compression: Option<Arc<compress::Stats>>, verdict: Option<first_bytes::Action>)
+where
+    L: AsyncRead + AsyncWrite + Send + 'static,
+    R: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let options = &service.options;
+    service.metrics.opened();
+
+    let registry = service.registry.clone();
+    let dropped = verdict == Some(first_bytes::Action::Drop);
+    let blocking_mode = dropped || (options.blocking_mode && verdict.is_none());
+    let conn = registry.register(peer_addr, local_port, backend.clone(), blocking_mode);
+    if dropped {
+        conn.set_blocking();
+    }
+    registry.record(registry::EventKind::Opened, conn.id, format!("{} -> {}", peer_addr, backend));
+    let client = Client {
+        addr: peer_addr,
+        blocking_mode,
+        local_port,
+        search: service.search.clone(),
+        remove_options: options.remove_options,
+        rule: service.rule.clone(),
+        login: service.profiles.select(&backend),
+        backend,
+        metrics: service.metrics.clone(),
+        registry,
+        conn,
+        compression,
+        filters: service.filters.clone(),
+    };
+    process_conn(local, remote, client, service.password.clone()).await;
+}
+
+/// Connect a stream opened by another tcpforward, through the reverse tunnel
+/// or a multiplexed connection, to the remote. `local_addr` is where the peer
+/// connected to, when it is known.
+pub(super) async fn accept_stream<S>(service: Arc<Service>, local: S, peer_addr: SocketAddr, local_addr: Option<SocketAddr>, kind: &'static str)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let date = chrono::Local::now();
+    report!("[{}] a new {} stream from {:?} is coming!", date.format("%m-%d %H:%M"), kind, peer_addr);
+    service.metrics.accepted();
+
+    let (mut local, local_compression): (Box<dyn Stream>, _) = if service.options.compress_local {
+        match compress::accept(local).await {
+            Ok((stream, stats)) => (Box::new(stream), Some(stats)),
+            Err(e) => {
+                report!("compression negotiation with {:?} error: {:?}", peer_addr, e.to_string());
+                service.metrics.refused();
+                return;
+            }
+        }
+    } else {
+        (Box::new(local), None)
+    };
+
+    let (first, verdict) = match screen(&service, &mut local, peer_addr).await {
+        Some(x) => x,
+        None => {
+            service.metrics.refused();
+            return;
+        }
+    };
+    let backend = match &verdict {
+        // a stream has no reset of its own, it is just closed
+        Some(first_bytes::Action::Reset) => {
+            service.metrics.refused();
+            return;
+        }
+        Some(first_bytes::Action::Redirect(remote)) => Some(remote.clone()),
+        _ => service.select_backend(peer_addr, SocketAddr::from(([0, 0, 0, 0], 0))),
+    };
+    let backend = match backend {
+        Some(backend) => Arc::new(backend),
+        None => {
+            report!("no backend is configured, dropping the {} stream", kind);
+            service.metrics.refused();
+            return;
+        }
+    };
+    let connect_start = Instant::now();
+    let remote = match dial(&service.options, &backend).await {
+        Ok((s, bound)) => compress_remote(&service.options, s).await.map(|(s, stats)| (s, bound, stats)),
+        Err(e) => Err(e),
+    };
+    let (mut remote, bound, remote_compression) = match remote {
+        Ok(s) => s,
+        Err(e) => {
+            report!("connect to remote error: {:?}", e.to_string());
+            service.metrics.refused();
+            return;
+        }
+    };
+    if let Some(version) = service.options.send_proxy {
+        let header = match local_addr {
+            Some(local_addr) => proxy_protocol::header(version, peer_addr, local_addr),
+            None => proxy_protocol::header_unknown(version),
+        };
+        if let Err(e) = remote.write_all(&header).await {
+            report!("send proxy header error: {:?}", e.to_string());
+            service.metrics.refused();
+            return;
+        }
+    }
+    service.metrics.connected(connect_start.elapsed());
+    let local_port = bound.map_or(0, |x| x.port());
+    forward(&service, Prefixed::new(first, local), remote, peer_addr, backend, local_port, local_compression.or(remote_compression), verdict).await;
+}
+
+/// Serve a connection from another tcpforward: decrypt it with --secure-local and
+/// split it into streams with --mux-accept.
+async fn accept_peer<S>(service: Arc<Service>, local: S, peer_addr: SocketAddr, local_addr: Option<SocketAddr>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let options = &service.options;
+    let local: Box<dyn Stream> = match options.psk.as_deref() {
+        Some(psk) if options.secure_local => match secure::wrap(local, psk.as_bytes(), false).await {
+            Ok(secured) => Box::new(secured),
+            Err(e) => {
+                report!("secure handshake with {:?} error: {:?}", peer_addr, e.to_string());
+                service.metrics.refused();
+                return;
+            }
+        },
+        _ => Box::new(local),
+    };
+    if !options.mux_accept {
+        let kind = if options.secure_local { "secure" } else { "compressed" };
+        accept_stream(service.clone(), local, peer_addr, local_addr, kind).await;
+        return;
+    }
+
+    let date = chrono::Local::now();
+    report!("[{}] a new mux session {:?} is coming!", date.format("%m-%d %H:%M"), peer_addr);
+    let (_session, mut streams) = mux::Session::new(local, false);
+    while let Some(stream) = streams.recv().await {
+        tokio::spawn(accept_stream(service.clone(), stream, peer_addr, local_addr, "mux"));
+    }
+    let date = chrono::Local::now();
+    report!("[{}] mux session {:?} is closed", date.format("%m-%d %H:%M"), peer_addr);
+}
+
+pub(super) async fn serve_unix(service: Arc<Service>, listener: UnixListener) -> io::Result<()> {
+    // unix peers have no address, the registry still wants one
+    let peer_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    loop {
+        let (local, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // out of descriptors most likely, the listener itself is fine
+            Err(e) => {
+                report!("accept error: {:?}", e.to_string());
+                tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                continue
+            }
+        };
+        if service.options.mux_accept || service.options.secure_local || service.options.compress_local {
+            tokio::spawn(accept_peer(service.clone(), local, peer_addr, None));
+        } else {
+            tokio::spawn(accept_stream(service.clone(), local, peer_addr, None, "unix"));
+        }
+    }
+}
+
+pub(super) async fn serve(service: Arc<Service>, listener: TcpListener) -> io::Result<()> {
+    let listening = listener.local_addr()?;
+    loop {
+        let (local, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // out of descriptors most likely, the listener itself is fine
+            Err(e) => {
+                report!("accept error: {:?}", e.to_string());
+                tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                continue
+            }
+        };
+        if service.options.mux_accept || service.options.secure_local || service.options.compress_local {
+            let local_addr = local.local_addr().ok();
+            tokio::spawn(accept_peer(service.clone(), local, peer_addr, local_addr));
+        } else {
+            tokio::spawn(accept_conn(service.clone(), local, peer_addr, listening));
+        }
+    }
+}
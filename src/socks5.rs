@@ -0,0 +1,205 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("socks5: {}", message))
+}
+
+async fn send_reply(stream: &mut TcpStream, code: u8, bound: SocketAddr) -> io::Result<()> {
+    let mut reply = vec![VERSION, code, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(ATYP_IPV4);
+            reply.extend(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(ATYP_IPV6);
+            reply.extend(&ip.octets());
+        }
+    }
+    reply.extend(&bound.port().to_be_bytes());
+    stream.write_all(&reply).await
+}
+
+async fn authenticate(stream: &mut TcpStream, user: &str, password: &str) -> io::Result<()> {
+    // RFC 1929 sub-negotiation
+    let version = stream.read_u8().await?;
+    if version != 0x01 {
+        return Err(invalid("bad auth version"))
+    }
+    let mut given_user = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut given_user).await?;
+    let mut given_password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut given_password).await?;
+
+    if given_user == user.as_bytes() && given_password == password.as_bytes() {
+        stream.write_all(&[0x01, 0x00]).await
+    } else {
+        stream.write_all(&[0x01, 0x01]).await?;
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "socks5: bad username or password"))
+    }
+}
+
+/// Run the server side of the handshake and return the `host:port` the client
+/// asked to connect to. Only `CONNECT` is supported.
+pub(super) async fn handshake(stream: &mut TcpStream, credentials: Option<(&str, &str)>) -> io::Result<String> {
+    if stream.read_u8().await? != VERSION {
+        return Err(invalid("not a socks5 client"))
+    }
+    let mut methods = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let wanted = if credentials.is_some() { USER_PASSWORD } else { NO_AUTH };
+    if !methods.contains(&wanted) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(invalid("no acceptable auth method"))
+    }
+    stream.write_all(&[VERSION, wanted]).await?;
+    if let Some((user, password)) = credentials {
+        authenticate(stream, user, password).await?;
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+    if request[0] != VERSION {
+        return Err(invalid("bad request version"))
+    }
+    if request[1] != CONNECT {
+        send_reply(stream, REPLY_COMMAND_NOT_SUPPORTED, unspecified).await?;
+        return Err(invalid("only CONNECT is supported"))
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| invalid("domain is not utf-8"))?
+        }
+        _ => {
+            send_reply(stream, REPLY_ADDRESS_NOT_SUPPORTED, unspecified).await?;
+            return Err(invalid("unknown address type"))
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Tell the client how connecting to its destination went.
+pub(super) async fn reply(stream: &mut TcpStream, result: Result<SocketAddr, &io::Error>) -> io::Result<()> {
+    match result {
+        Ok(bound) => send_reply(stream, 0x00, bound).await,
+        Err(e) => {
+            let code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
+                _ => REPLY_GENERAL_FAILURE,
+            };
+            send_reply(stream, code, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Both ends of a loopback connection, the client first.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        (client, listener.accept().await.unwrap().0)
+    }
+
+    /// What the server answers to `sent` and what its handshake made of it.
+    async fn handshake_with(sent: &[u8], credentials: Option<(&str, &str)>) -> (Vec<u8>, io::Result<String>) {
+        let (mut client, mut server) = pair().await;
+        client.write_all(sent).await.unwrap();
+        let result = handshake(&mut server, credentials).await;
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (answer, result)
+    }
+
+    #[tokio::test]
+    async fn the_destination_may_be_an_address_or_a_name() {
+        let (answer, dst) = handshake_with(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80], None).await;
+        assert_eq!(answer, [5, 0]);
+        assert_eq!(dst.unwrap(), "10.0.0.1:80");
+
+        let (_, dst) = handshake_with(b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb", None).await;
+        assert_eq!(dst.unwrap(), "example.com:443");
+
+        let mut sent = vec![5, 1, 0, 5, 1, 0, 4];
+        sent.extend(Ipv6Addr::LOCALHOST.octets());
+        sent.extend([0, 22]);
+        let (_, dst) = handshake_with(&sent, None).await;
+        assert_eq!(dst.unwrap(), "[::1]:22");
+    }
+
+    #[tokio::test]
+    async fn credentials_are_checked() {
+        let sent = b"\x05\x01\x02\x01\x03bob\x06secret\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+        let (answer, dst) = handshake_with(sent, Some(("bob", "secret"))).await;
+        assert_eq!(answer, [5, 2, 1, 0]);
+        assert_eq!(dst.unwrap(), "127.0.0.1:80");
+
+        let (answer, dst) = handshake_with(b"\x05\x01\x02\x01\x03bob\x05wrong", Some(("bob", "secret"))).await;
+        assert_eq!(answer, [5, 2, 1, 1]);
+        assert_eq!(dst.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // a client that can not authenticate is turned away
+        let (answer, dst) = handshake_with(&[5, 1, 0], Some(("bob", "secret"))).await;
+        assert_eq!(answer, [5, 0xff]);
+        assert!(dst.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_connect_is_supported() {
+        let (answer, dst) = handshake_with(&[5, 1, 0, 5, 2, 0, 1], None).await;
+        assert_eq!(answer, [5, 0, 5, REPLY_COMMAND_NOT_SUPPORTED, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(dst.is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_tell_how_connecting_went() {
+        let (mut client, mut server) = pair().await;
+        reply(&mut server, Ok("127.0.0.1:8080".parse().unwrap())).await.unwrap();
+        reply(&mut server, Err(&io::Error::from(io::ErrorKind::ConnectionRefused))).await.unwrap();
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90, 5, REPLY_CONNECTION_REFUSED, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+}
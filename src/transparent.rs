@@ -0,0 +1,142 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    /// Same value as `SO_ORIGINAL_DST`, linux/netfilter_ipv6/ip6_tables.h
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    pub(super) fn original_dst<S: AsRawFd>(socket: &S, ipv6: bool) -> io::Result<SocketAddr> {
+        let fd = socket.as_raw_fd();
+        unsafe {
+            if ipv6 {
+                let mut addr: libc::sockaddr_in6 = mem::zeroed();
+                let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+                if libc::getsockopt(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len) != 0 {
+                    return Err(io::Error::last_os_error())
+                }
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+            } else {
+                let mut addr: libc::sockaddr_in = mem::zeroed();
+                let mut len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                if libc::getsockopt(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST, &mut addr as *mut _ as *mut libc::c_void, &mut len) != 0 {
+                    return Err(io::Error::last_os_error())
+                }
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+        }
+    }
+
+    pub(super) fn set_transparent<S: AsRawFd>(socket: &S, ipv6: bool) -> io::Result<()> {
+        let enable: libc::c_int = 1;
+        let (level, name) = if ipv6 {
+            (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+        } else {
+            (libc::SOL_IP, libc::IP_TRANSPARENT)
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::SocketAddr;
+
+    fn unsupported() -> io::Error {
+        io::Error::other("transparent proxying is only supported on linux")
+    }
+
+    pub(super) fn original_dst<S>(_: &S, _: bool) -> io::Result<SocketAddr> {
+        Err(unsupported())
+    }
+
+    pub(super) fn set_transparent<S>(_: &S, _: bool) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+/// Whether `dst` is where `listener` accepts, connecting there would only
+/// come back to us.
+fn is_listener(dst: SocketAddr, listener: SocketAddr) -> bool {
+    dst.port() == listener.port() && (dst.ip() == listener.ip() || listener.ip().is_unspecified() && is_local(dst.ip()))
+}
+
+/// Whether `ip` is an address of this host, which only those can be bound to.
+fn is_local(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+fn ourselves() -> io::Error {
+    io::Error::other("connection was not diverted, refusing to connect to ourselves")
+}
+
+/// The destination a connection had before iptables `REDIRECT` sent it to
+/// `listener`.
+pub(super) fn original_dst(stream: &TcpStream, listener: SocketAddr) -> io::Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+    let dst = sys::original_dst(stream, local_addr.is_ipv6())?;
+    if dst == local_addr || is_listener(dst, listener) {
+        return Err(ourselves())
+    }
+    Ok(dst)
+}
+
+/// The destination of a connection a `TPROXY` rule diverted to `listener`,
+/// which it keeps as its local address.
+pub(super) fn tproxy_dst(local_addr: SocketAddr, listener: SocketAddr) -> io::Result<SocketAddr> {
+    if is_listener(local_addr, listener) {
+        return Err(ourselves())
+    }
+    Ok(local_addr)
+}
+
+/// Listen with `IP_TRANSPARENT` so connections diverted by a `TPROXY` rule
+/// are accepted with their original destination as local address.
+pub(super) fn bind_tproxy(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { tokio::net::TcpSocket::new_v4()? } else { tokio::net::TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    sys::set_transparent(&socket, addr.is_ipv6())?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_listener_is_no_destination() {
+        let listener: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert!(tproxy_dst(listener, listener).is_err());
+        let dst = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(tproxy_dst(dst, listener).unwrap(), dst);
+
+        // a listener on every address is reached on any of this host
+        let listener = "0.0.0.0:8080".parse().unwrap();
+        assert!(tproxy_dst("127.0.0.1:8080".parse().unwrap(), listener).is_err());
+        let dst = "192.0.2.1:8080".parse().unwrap();
+        assert_eq!(tproxy_dst(dst, listener).unwrap(), dst);
+    }
+}
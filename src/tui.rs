@@ -0,0 +1,150 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, Row, Table};
+use ratatui::Frame;
+
+use crate::registry::{EventKind, Registry};
+
+/// How often the dashboard samples the counters and redraws.
+const TICK: Duration = Duration::from_millis(500);
+
+/// Number of samples shown in a throughput sparkline.
+const HISTORY: usize = 30;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Default)]
+struct Throughput {
+    last: u64,
+    samples: VecDeque<u64>,
+}
+
+impl Throughput {
+    fn sample(&mut self, total: u64) {
+        if self.samples.len() == HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(total - self.last);
+        self.last = total;
+    }
+
+    fn rate(&self) -> u64 {
+        self.samples.back().map_or(0, |x| x * 1000 / TICK.as_millis() as u64)
+    }
+
+    fn sparkline(&self) -> String {
+        let max = self.samples.iter().copied().max().unwrap_or(0).max(1);
+        self.samples.iter().map(|&x| BARS[(x * (BARS.len() as u64 - 1) / max) as usize]).collect()
+    }
+}
+
+fn human(bytes: u64) -> String {
+    match bytes {
+        n if n >= 1 << 30 => format!("{:.1}G", n as f64 / (1u64 << 30) as f64),
+        n if n >= 1 << 20 => format!("{:.1}M", n as f64 / (1u64 << 20) as f64),
+        n if n >= 1 << 10 => format!("{:.1}K", n as f64 / (1u64 << 10) as f64),
+        n => format!("{}B", n),
+    }
+}
+
+fn events(registry: &Registry, kind: EventKind, title: &'static str, height: usize) -> List<'static> {
+    let items: Vec<ListItem> = registry.recent(kind, height).into_iter().map(|event| {
+        let text: String = event.text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        ListItem::new(format!("{} #{} {}", event.at.format("%H:%M:%S"), event.conn, text))
+    }).collect();
+    List::new(items).block(Block::default().borders(Borders::ALL).title(title))
+}
+
+fn draw(frame: &mut Frame, registry: &Registry, history: &HashMap<u64, Throughput>) {
+    let [top, bottom] = Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(frame.area());
+    let [hits, requests] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(bottom);
+
+    let connections = registry.connections();
+    let rows: Vec<Row> = connections.iter().map(|conn| {
+        let throughput = history.get(&conn.id);
+        Row::new(vec![
+            conn.id.to_string(),
+            conn.peer.to_string(),
+            conn.local_port.to_string(),
+            conn.backend.to_string(),
+            match (conn.blocking(), conn.matched()) {
+                (Some(true), _) => "blocking",
+                (_, true) => "matched",
+                (Some(false), false) => "watching",
+                (None, false) => "",
+            }.to_owned(),
+            format!("{}s", conn.started.elapsed().as_secs()),
+            human(conn.bytes_out.load(Ordering::Relaxed)),
+            human(conn.bytes_in.load(Ordering::Relaxed)),
+            format!("{}/s", human(throughput.map_or(0, Throughput::rate))),
+            throughput.map(Throughput::sparkline).unwrap_or_default(),
+        ])
+    }).collect();
+    let widths = [
+        Constraint::Length(5),
+        Constraint::Length(22),
+        Constraint::Length(6),
+        Constraint::Length(22),
+        Constraint::Length(9),
+        Constraint::Length(7),
+        Constraint::Length(8),
+        Constraint::Length(8),
+        Constraint::Length(10),
+        Constraint::Min(HISTORY as u16),
+    ];
+    let header = Row::new(vec!["id", "peer", "port", "backend", "state", "age", "out", "in", "rate", "throughput"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let title = format!("connections ({}) - press q to quit", connections.len());
+    frame.render_widget(Table::new(rows, widths).header(header).block(Block::default().borders(Borders::ALL).title(title)), top);
+
+    let height = hits.height.saturating_sub(2) as usize;
+    frame.render_widget(events(registry, EventKind::SearchHit, "search hits", height), hits);
+    frame.render_widget(events(registry, EventKind::Request, "http requests", height), requests);
+}
+
+fn run(registry: Arc<Registry>) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut history: HashMap<u64, Throughput> = HashMap::new();
+
+    loop {
+        let connections = registry.connections();
+        history.retain(|id, _| connections.iter().any(|x| x.id == *id));
+        for conn in &connections {
+            let total = conn.bytes_out.load(Ordering::Relaxed) + conn.bytes_in.load(Ordering::Relaxed);
+            history.entry(conn.id).or_default().sample(total);
+        }
+
+        terminal.draw(|frame| draw(frame, &registry, &history))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Char('q') || key.code == KeyCode::Esc || ctrl_c {
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// Take over the terminal and draw the dashboard until the user quits, which
+/// also stops the forwarder.
+pub(super) fn start(registry: Arc<Registry>) -> io::Result<()> {
+    crate::VERBOSE.store(false, Ordering::Relaxed);
+    std::thread::Builder::new().name(String::from("tui")).spawn(move || {
+        let result = run(registry);
+        ratatui::restore();
+        if let Err(e) = result {
+            eprintln!("tui error: {:?}", e.to_string());
+        }
+        std::process::exit(0)
+    })?;
+    Ok(())
+}
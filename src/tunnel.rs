@@ -0,0 +1,215 @@
+//! Reverse tunnel for devices behind NAT.
+//!
+//! The agent runs next to the device and keeps one connection open to the
+//! server, encrypted and authenticated with --psk like --secure-remote does,
+//! so only an agent knowing the key is taken. The connection is multiplexed
+//! like --mux does: for every client the server accepts on its public port it
+//! opens a stream to the agent, which pairs it with a fresh connection to the
+//! device. Nothing on the device network has to be reachable from outside.
+//!
+//! Every stream starts with one line from the server: `OPEN <client> <local>`
+//! for a client, with its address and the one it connected to, or `PING`,
+//! which the agent answers with `PONG`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+
+use crate::{mux, secure};
+
+/// How long a stream may take to say what it is for.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the server checks the agent is alive.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Read one `\n` terminated line without reading past it.
+async fn read_line<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            break
+        }
+        if line.len() >= 128 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tunnel: line is too long"))
+        }
+        line.push(byte);
+    }
+    String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "tunnel: line is not text"))
+}
+
+/// The public side of the tunnel.
+#[derive(Default)]
+pub(super) struct TunnelServer {
+    psk: Vec<u8>,
+    agent: Mutex<Option<mux::Session>>,
+}
+
+impl TunnelServer {
+    /// Start accepting agents knowing `psk` on `addr`.
+    pub(super) async fn listen(addr: &str, psk: &str) -> io::Result<Arc<Self>> {
+        let listener = TcpListener::bind(addr).await?;
+        let date = chrono::Local::now();
+        report!("[{}] waiting for tunnel agents on {}", date.format("%m-%d %H:%M"), addr);
+
+        let server = Arc::new(Self { psk: psk.as_bytes().to_vec(), ..Self::default() });
+        let accepting = server.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer_addr)) => {
+                        let server = accepting.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = server.handle(stream, peer_addr).await {
+                                report!("tunnel connection from {:?} error: {:?}", peer_addr, e.to_string());
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        report!("tunnel accept error: {:?}", e.to_string());
+                        tokio::time::sleep(crate::net::ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    async fn handle(&self, stream: tokio::net::TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+        // a peer without the key never gets past this
+        let link = secure::wrap(stream, &self.psk, false).await?;
+        let (session, _) = mux::Session::new(link, false);
+        let date = chrono::Local::now();
+        report!("[{}] tunnel agent {:?} is connected", date.format("%m-%d %H:%M"), peer_addr);
+        // a reconnecting agent replaces the previous one
+        *self.agent.lock().unwrap() = Some(session.clone());
+
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.tick().await;
+        let result = loop {
+            ping.tick().await;
+            if session.is_closed() {
+                break Ok(())
+            }
+            if let Err(e) = tokio::time::timeout(PING_INTERVAL, Self::ping(&session)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel: agent stopped answering"))) {
+                break Err(e)
+            }
+        };
+        session.close();
+
+        let mut agent = self.agent.lock().unwrap();
+        if agent.as_ref().is_some_and(|x| x.same(&session)) {
+            *agent = None;
+        }
+        let date = chrono::Local::now();
+        report!("[{}] tunnel agent {:?} is gone", date.format("%m-%d %H:%M"), peer_addr);
+        result
+    }
+
+    async fn ping(session: &mux::Session) -> io::Result<()> {
+        let mut stream = session.open().await?;
+        stream.write_all(b"PING\n").await?;
+        match read_line(&mut stream).await?.as_str() {
+            "PONG" => Ok(()),
+            line => Err(io::Error::new(io::ErrorKind::InvalidData, format!("tunnel: agent answered {:?}", line))),
+        }
+    }
+
+    /// Ask the agent for a new stream to the device, for `client` that
+    /// connected to `local`.
+    pub(super) async fn open(&self, client: SocketAddr, local: SocketAddr) -> io::Result<DuplexStream> {
+        let agent = self.agent.lock().unwrap().clone();
+        let session = match agent {
+            Some(session) if !session.is_closed() => session,
+            _ => return Err(io::Error::new(io::ErrorKind::NotConnected, "tunnel: no agent is connected")),
+        };
+        let mut stream = session.open().await?;
+        stream.write_all(format!("OPEN {} {}\n", client, local).as_bytes()).await?;
+        Ok(stream)
+    }
+}
+
+/// Read the line a stream of the server starts with, answering pings.
+/// `None` for a ping, otherwise the client and the address it connected to.
+async fn greeting(stream: &mut DuplexStream) -> io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = tokio::time::timeout(OPEN_TIMEOUT, read_line(stream)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tunnel: no greeting"))??;
+    if line == "PING" {
+        stream.write_all(b"PONG\n").await?;
+        return Ok(None)
+    }
+    let addrs = line.strip_prefix("OPEN ").and_then(|x| x.split_once(' '));
+    match addrs.map(|(client, local)| (client.parse(), local.parse())) {
+        Some((Ok(client), Ok(local))) => Ok(Some((client, local))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("tunnel: unexpected greeting {:?}", line))),
+    }
+}
+
+async fn agent_session<F>(server: &str, psk: &[u8], handler: &Arc<F>) -> io::Result<()>
+where
+    F: Fn(DuplexStream, SocketAddr, SocketAddr) + Send + Sync + 'static,
+{
+    let conn = crate::net::connect(server).await?;
+    let link = secure::wrap(conn, psk, true).await?;
+    let (session, mut streams) = mux::Session::new(link, true);
+    let date = chrono::Local::now();
+    report!("[{}] tunnel to {} is up", date.format("%m-%d %H:%M"), server);
+
+    // a server that stopped pinging is gone, even when the connection looks fine
+    let (pinged, mut pings) = tokio::sync::mpsc::unbounded_channel();
+    let mut deadline = tokio::time::Instant::now() + PING_INTERVAL * 2;
+    let result = loop {
+        tokio::select! {
+            stream = streams.recv() => {
+                let mut stream = match stream {
+                    Some(stream) => stream,
+                    None => break Ok(()),
+                };
+                let handler = handler.clone();
+                let pinged = pinged.clone();
+                tokio::spawn(async move {
+                    match greeting(&mut stream).await {
+                        Ok(Some((client, local))) => handler(stream, client, local),
+                        Ok(None) => { let _ = pinged.send(()); }
+                        Err(e) => report!("tunnel stream error: {:?}", e.to_string()),
+                    }
+                });
+            }
+            Some(()) = pings.recv() => deadline = tokio::time::Instant::now() + PING_INTERVAL * 2,
+            _ = tokio::time::sleep_until(deadline) => break Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel: server stopped pinging")),
+        }
+    };
+    session.close();
+    result
+}
+
+/// Keep a connection to the tunnel `server` open forever, handing every
+/// stream it opens to `handler` with the client and the address it
+/// connected to.
+pub(super) async fn run_agent<F>(server: String, psk: String, handler: F)
+where
+    F: Fn(DuplexStream, SocketAddr, SocketAddr) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = std::time::Instant::now();
+        let result = agent_session(&server, psk.as_bytes(), &handler).await;
+        let date = chrono::Local::now();
+        match result {
+            Ok(()) => report!("[{}] tunnel to {} is closed", date.format("%m-%d %H:%M"), server),
+            Err(e) => report!("[{}] tunnel to {} error: {:?}", date.format("%m-%d %H:%M"), server, e.to_string()),
+        }
+        if started.elapsed() > PING_INTERVAL * 2 {
+            backoff = Duration::from_secs(1);
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
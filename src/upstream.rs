@@ -0,0 +1,222 @@
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::net::{self, split_host_port};
+
+/// How long the proxy may take to connect, like the socks5 and http CONNECT
+/// front-ends give their clients.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A proxy the remote is dialed through, parsed from
+/// `socks5://[user:password@]host:port` or `http://[user:password@]host:port`.
+#[derive(Clone, Debug)]
+pub enum Via {
+    Socks5 { addr: String, credentials: Option<(String, String)> },
+    Http { addr: String, credentials: Option<(String, String)> },
+}
+
+impl FromStr for Via {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.find("://") {
+            Some(idx) => (&s[..idx], &s[idx + 3..]),
+            None => return Err(format!("{:?} should look like socks5://host:port or http://host:port", s)),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, addr) = match rest.rfind('@') {
+            Some(idx) => {
+                let userinfo = &rest[..idx];
+                let (user, password) = match userinfo.find(':') {
+                    Some(colon) => (&userinfo[..colon], &userinfo[colon + 1..]),
+                    None => (userinfo, ""),
+                };
+                (Some((user.to_owned(), password.to_owned())), rest[idx + 1..].to_owned())
+            }
+            None => (None, rest.to_owned()),
+        };
+        if split_host_port(&addr).is_none() {
+            return Err(format!("{:?} has no port", s))
+        }
+        match scheme {
+            // the username/password auth of socks5 has a length byte for each
+            "socks5" | "socks5h" if credentials.as_ref().is_some_and(|(user, password)| user.len() > 255 || password.len() > 255) => {
+                Err(String::from("socks5 usernames and passwords are at most 255 bytes"))
+            }
+            "socks5" | "socks5h" => Ok(Via::Socks5 { addr, credentials }),
+            "http" => Ok(Via::Http { addr, credentials }),
+            _ => Err(format!("unsupported proxy scheme {:?}, expected socks5 or http", scheme)),
+        }
+    }
+}
+
+fn failed(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("socks5 proxy {}", message))
+}
+
+async fn socks5_connect(stream: &mut TcpStream, target: &str, credentials: Option<&(String, String)>) -> io::Result<()> {
+    let (host, port) = split_host_port(target).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "target has no port"))?;
+
+    if credentials.is_some() {
+        stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?;
+    } else {
+        stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    }
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != 0x05 {
+        return Err(invalid("answered with another version"))
+    }
+    match (choice[1], credentials) {
+        (0x00, _) => {}
+        (0x02, Some((user, password))) => {
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend(password.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[0] != 0x01 {
+                return Err(invalid("answered the credentials with another version"))
+            }
+            if status[1] != 0x00 {
+                return Err(failed(String::from("socks5 proxy rejected the credentials")))
+            }
+        }
+        _ => return Err(failed(String::from("socks5 proxy offers no usable auth method"))),
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend(&ip.octets());
+        }
+        Err(_) if host.len() > 255 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "socks5 host names are at most 255 bytes")),
+        Err(_) => {
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(invalid("replied with another version"))
+    }
+    if reply[1] != 0x00 {
+        return Err(failed(format!("socks5 proxy could not connect to {}, reply {}", target, reply[1])))
+    }
+    let bound_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        _ => return Err(failed(String::from("socks5 proxy sent a bad reply"))),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(stream: &mut TcpStream, target: &str, credentials: Option<&(String, String)>) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((user, password)) = credentials {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing the remote sends after the header is lost
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= 8192 {
+            return Err(failed(String::from("http proxy response header is too long")))
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(header.split(|&b| b == b'\r').next().unwrap_or(&[])).into_owned();
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(failed(format!("http proxy could not connect to {}: {}", target, status_line))),
+    }
+}
+
+/// Connect to `target`, directly or through `via`.
+pub(super) async fn connect(target: &str, via: Option<&Via>) -> io::Result<TcpStream> {
+    let (addr, mut stream) = match via {
+        None => return net::connect(target).await,
+        Some(Via::Socks5 { addr, .. } | Via::Http { addr, .. }) => (addr, net::connect(addr).await?),
+    };
+    let handshake = async {
+        match via {
+            Some(Via::Socks5 { credentials, .. }) => socks5_connect(&mut stream, target, credentials.as_ref()).await,
+            Some(Via::Http { credentials, .. }) => http_connect(&mut stream, target, credentials.as_ref()).await,
+            None => Ok(()),
+        }
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(result) => result.map(|_| stream),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("proxy {} did not connect to {} in time", addr, target))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A proxy that answers every client with `answer` and then keeps it waiting.
+    async fn proxy(answer: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(answer).await;
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_proxy_times_out() {
+        for via in ["socks5", "http"] {
+            let via: Via = format!("{}://{}", via, proxy(b"").await).parse().unwrap();
+            let e = connect("example.com:443", Some(&via)).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_answers_of_another_version_are_refused() {
+        let via: Via = format!("socks5://{}", proxy(&[0x04, 0x00]).await).parse().unwrap();
+        let e = connect("example.com:443", Some(&via)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let via: Via = format!("socks5://{}", proxy(&[0x05, 0x00, 0x04, 0x00, 0x00, 0x01]).await).parse().unwrap();
+        let e = connect("example.com:443", Some(&via)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,957 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use structopt::StructOpt;
+use tcpforward::{Builder, Client, Connection, Direction, EventKind, Options, Registry, StreamFilter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+
+struct Upper;
+
+impl StreamFilter for Upper {
+    fn on_chunk(&mut self, _conn: &Connection, chunk: &mut Vec<u8>) {
+        chunk.make_ascii_uppercase();
+    }
+}
+
+/// Appends `tail` once the stream ends.
+struct Trailer(&'static [u8]);
+
+impl StreamFilter for Trailer {
+    fn on_chunk(&mut self, _conn: &Connection, _chunk: &mut Vec<u8>) {}
+
+    fn on_eof(&mut self, _conn: &Connection) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+struct Panics;
+
+impl StreamFilter for Panics {
+    fn on_chunk(&mut self, _conn: &Connection, _chunk: &mut Vec<u8>) {
+        panic!("a bad filter")
+    }
+}
+
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                let _ = writer.shutdown().await;
+            });
+        }
+    });
+    addr
+}
+
+/// A builder listening on a free port and forwarding to `remote`.
+fn forwarding_to(remote: impl ToString) -> Builder {
+    Builder::new().listen("127.0.0.1:0").remote(&remote.to_string())
+}
+
+/// Build and run `builder`, returning where it listens and its registry.
+async fn start(builder: Builder) -> (SocketAddr, Arc<Registry>) {
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    let registry = forwarder.registry();
+    tokio::spawn(forwarder.run());
+    (addr, registry)
+}
+
+/// Send `data` over a new connection to `addr`, end it and read everything
+/// that comes back.
+async fn exchange(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    received
+}
+
+/// A loopback address nothing listens on yet.
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn process_conn_relays_both_directions_through_filters() {
+    tcpforward::set_verbose(false);
+    let (mut client_side, local) = tokio::io::duplex(1024);
+    let (remote, mut server_side) = tokio::io::duplex(1024);
+    let registry = Arc::new(Registry::new(Vec::new()));
+    let client = Client::new("127.0.0.1:1".parse().unwrap(), "test", registry.clone())
+        .with_filter(Direction::Out, |_| Box::new(Upper))
+        .with_filter(Direction::In, |_| Box::new(Trailer(b"!")));
+    let conn = client.connection().clone();
+    assert_eq!(registry.connections().len(), 1);
+    let relay = tokio::spawn(tcpforward::process_conn(local, remote, client, None));
+
+    client_side.write_all(b"hello").await.unwrap();
+    client_side.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    server_side.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"HELLO");
+
+    server_side.write_all(b"world").await.unwrap();
+    server_side.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    client_side.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"world!");
+
+    relay.await.unwrap();
+    assert_eq!(conn.bytes_out(), 5);
+    assert_eq!(conn.bytes_in(), 6);
+    assert!(registry.connections().is_empty());
+}
+
+#[tokio::test]
+async fn builder_forwards_and_reports_events() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let builder = forwarding_to(echo)
+        .filter(Direction::Out, |_| Box::new(Upper))
+        .on_event(move |event| seen.lock().unwrap().push(event.kind));
+    let (addr, _) = start(builder).await;
+
+    assert_eq!(exchange(addr, b"ping").await, b"PING");
+
+    // the forwarder records the close after the client saw the end of the stream
+    for _ in 0..50 {
+        if events.lock().unwrap().contains(&EventKind::Closed) {
+            break
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let events = events.lock().unwrap();
+    assert_eq!(events.first(), Some(&EventKind::Opened));
+    assert!(events.contains(&EventKind::Closed));
+}
+
+#[tokio::test]
+async fn remote_selector_picks_the_backend() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let builder = forwarding_to("127.0.0.1:1").remote_selector(move |_peer, _local| Some(echo.to_string()));
+    let (addr, _) = start(builder).await;
+
+    assert_eq!(exchange(addr, b"ping").await, b"ping");
+}
+
+#[tokio::test]
+async fn builder_takes_the_command_line_options() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let local = free_addr();
+    let options = Options::from_iter(["tcpforward", "--local-ip", "127.0.0.1", "--local-port", &local.port().to_string(),
+        "--remote-ip", "127.0.0.1", "--remote-port", &echo.port().to_string()]);
+    let forwarder = Builder::from_options(options).build().await.unwrap();
+    assert_eq!(forwarder.local_addrs(), [local]);
+    tokio::spawn(forwarder.run());
+
+    assert_eq!(exchange(local, b"ping").await, b"ping");
+}
+
+#[tokio::test]
+async fn blocking_mode_swallows_clients_without_the_dvr_magic() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new()
+        .listen("127.0.0.1:0")
+        .remote(&echo.to_string())
+        .filter(Direction::Out, |_| Box::new(Upper));
+    builder.options().blocking_mode = true;
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    let registry = forwarder.registry();
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"#let me through").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"#LET ME THROUGH");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert_eq!(registry.connections().iter().filter(|x| x.bytes_out() > 0).count(), 0);
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty());
+
+    // a client waiting for the remote to talk first is connected right away
+    // and judged whenever it talks, however long that takes
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(registry.connections().len(), 1);
+    stream.write_all(b"~late").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"~LATE");
+}
+
+#[tokio::test]
+async fn first_bytes_rules_pass_redirect_and_reset() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let other_addr = other.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = other.accept().await {
+            let _ = stream.write_all(b"other").await;
+        }
+    });
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().first_bytes.push("http:GET=pass".parse().unwrap());
+    builder.options().first_bytes.push(format!("line:^SSH-=redirect:{}", other_addr).parse().unwrap());
+    builder.options().first_bytes.push("any=reset".parse().unwrap());
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"GET / HTTP/1.0\r\n\r\n");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"SSH-2.0-OpenSSH_9.2\r\n").await.unwrap();
+    let mut received = [0; 5];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"other");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"POST / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    let reset = stream.read_to_end(&mut received).await.unwrap_err();
+    assert_eq!(reset.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+#[tokio::test]
+async fn routes_share_a_port_between_protocols() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let other_addr = other.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = other.accept().await {
+            let _ = stream.write_all(b"other").await;
+        }
+    });
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().route.push(format!("rtsp={}", other_addr).parse().unwrap());
+    builder.options().route.push(format!("silent={}", other_addr).parse().unwrap());
+    builder.options().first_bytes_timeout = Some(100);
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"OPTIONS rtsp://camera/live RTSP/1.0\r\nCSeq: 1\r\n\r\n").await.unwrap();
+    let mut received = [0; 5];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"other");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"OPTIONS / HTTP/1.1").await.unwrap();
+    let mut received = [0; 18];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"OPTIONS / HTTP/1.1");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut received = [0; 5];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"other");
+}
+
+#[tokio::test]
+async fn script_rewrites_http_messages() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let path = std::env::temp_dir().join(format!("tcpforward-test-{}.rhai", std::process::id()));
+    std::fs::write(&path, r#"
+        fn on_http(conn, msg) {
+            if conn.direction == "out" {
+                let body = blob(5);
+                body.write_ascii(0, 5, "hello");
+                msg.body = body;
+                return msg;
+            }
+        }
+    "#).unwrap();
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().script.push(path.to_string_lossy().into_owned());
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(received, b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+}
+
+#[cfg(feature = "wasm")]
+#[tokio::test]
+async fn wasm_plugin_filters_both_directions() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    // upper cases every chunk and adds a "!" at the end
+    let path = std::env::temp_dir().join(format!("tcpforward-test-{}.wat", std::process::id()));
+    std::fs::write(&path, r#"
+        (module
+          (import "env" "log" (func $log (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "closed!")
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $need i32)
+            (local.set $need (i32.sub
+              (i32.shr_u (i32.add (local.get $len) (i32.const 66559)) (i32.const 16))
+              (memory.size)))
+            (if (i32.gt_s (local.get $need) (i32.const 0))
+              (then (drop (memory.grow (local.get $need)))))
+            (i32.const 1024))
+          (func (export "on_data") (param $ptr i32) (param $len i32) (result i64)
+            (local $i i32) (local $c i32)
+            (block $done (loop $next
+              (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+              (local.set $c (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+              (if (i32.and (i32.ge_u (local.get $c) (i32.const 97)) (i32.le_u (local.get $c) (i32.const 122)))
+                (then (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (i32.sub (local.get $c) (i32.const 32)))))
+              (local.set $i (i32.add (local.get $i) (i32.const 1)))
+              (br $next)))
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32)) (i64.extend_i32_u (local.get $len))))
+          (func (export "on_close") (result i64)
+            (call $log (i32.const 0) (i32.const 7))
+            (i64.or (i64.shl (i64.const 6) (i64.const 32)) (i64.const 1))))
+    "#).unwrap();
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().wasm.push(path.to_string_lossy().into_owned());
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(received, b"PING!!");
+}
+
+#[tokio::test]
+async fn search_reports_the_direction_and_offset_of_a_match() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().search_regex.push(String::from("in:W[a-z]+D"));
+    builder.options().ignore_case = true;
+    builder.options().context = Some(2);
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    let registry = forwarder.registry();
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"hello world!").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    let hits = registry.recent(EventKind::SearchHit, 10);
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].text, "in @6 o world!");
+}
+
+#[tokio::test]
+async fn search_matches_across_chunks_and_directions() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().search.push(String::from("out:secret"));
+    builder.options().search.push(String::from("in:tok"));
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    let registry = forwarder.registry();
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"a sec").await.unwrap();
+    let mut echoed = [0; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert!(registry.recent(EventKind::Matched, 10).is_empty());
+    stream.write_all(b"ret tok").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(registry.recent(EventKind::SearchHit, 10).len(), 2);
+    let matched = registry.recent(EventKind::Matched, 10);
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].text, "out:secret, in:tok");
+}
+
+#[tokio::test]
+async fn search_logs_a_match_growing_across_chunks_once() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().search_regex.push(String::from("out:\\d+"));
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    let registry = forwarder.registry();
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"pin 123").await.unwrap();
+    let mut echoed = [0; 7];
+    stream.read_exact(&mut echoed).await.unwrap();
+    stream.write_all(b"45 and 6").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    let hits = registry.recent(EventKind::SearchHit, 10);
+    assert_eq!(hits.len(), 2);
+    assert!(hits.iter().any(|x| x.text.starts_with("out @4 ")));
+    assert!(hits.iter().any(|x| x.text.starts_with("out @14 ")));
+}
+
+#[tokio::test]
+async fn search_actions_post_capture_and_block() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let hook = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dir = std::env::temp_dir().join(format!("tcpforward-capture-{}", std::process::id()));
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&echo.to_string());
+    builder.options().search.push(String::from("out:evil"));
+    builder.options().search_action.push(String::from("out:evil=webhook,capture,block"));
+    builder.options().webhook = Some(format!("http://{}/hits", hook.local_addr().unwrap()).parse().unwrap());
+    builder.options().capture_dir = Some(dir.clone());
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut echoed = [0; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+    stream.write_all(b"evil").await.unwrap();
+
+    let (mut posted, _) = hook.accept().await.unwrap();
+    let mut request = vec![0; 4096];
+    let n = posted.read(&mut request).await.unwrap();
+    posted.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+    let request = String::from_utf8_lossy(&request[..n]);
+    assert!(request.starts_with("POST /hits HTTP/1.1\r\n"));
+    assert!(request.contains("\"pattern\":\"out:evil\""));
+
+    stream.write_all(b"more").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty());
+
+    // the capture is written in the background
+    let mut written = Vec::new();
+    for _ in 0..50 {
+        let captured: Vec<_> = std::fs::read_dir(&dir).into_iter().flatten().flatten().map(|x| x.path()).collect();
+        let out = captured.iter().find(|x| x.to_string_lossy().ends_with("-out.bin"));
+        written = out.and_then(|x| std::fs::read(x).ok()).unwrap_or_default();
+        if written == b"evilmore" {
+            break
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(written, b"evilmore");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_panicking_filter_only_closes_its_connection() {
+    tcpforward::set_verbose(false);
+    let (mut client_side, local) = tokio::io::duplex(1024);
+    let (remote, mut server_side) = tokio::io::duplex(1024);
+    let registry = Arc::new(Registry::new(Vec::new()));
+    let client = Client::new("127.0.0.1:1".parse().unwrap(), "test", registry.clone())
+        .with_filter(Direction::Out, |_| Box::new(Panics));
+    let relay = tokio::spawn(tcpforward::process_conn(local, remote, client, None));
+
+    client_side.write_all(&[0xff, 0xfe, 0x00]).await.unwrap();
+    let mut received = Vec::new();
+    server_side.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty());
+
+    relay.await.unwrap();
+    assert!(registry.connections().is_empty());
+}
+
+#[tokio::test]
+async fn login_profiles_rewrite_the_pages_of_their_remote() {
+    tcpforward::set_verbose(false);
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = server.local_addr().unwrap();
+    let dir = std::env::temp_dir().join(format!("tcpforward-login-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("submit.js"), "submit('{username}')").unwrap();
+    std::fs::write(dir.join("profiles.ini"), format!("
+        # the web UI of the test server
+        [web]
+        remote = {}
+        username = bob
+        password = secret
+        path = /login
+        remove-header = X-Frame-Options
+        replace = getCredentials() => ['{{username}}','{{password}}']
+        script = submit.js
+    ", remote)).unwrap();
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&remote.to_string());
+    builder.options().login_profiles = Some(dir.join("profiles.ini").to_string_lossy().into_owned());
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let served = tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let n = stream.read(&mut request).await.unwrap();
+        let body = "<html><body>var c=getCredentials();</body></html>";
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nX-Frame-Options: DENY\r\nContent-Length: {}\r\n\r\n", body.len());
+        // the body comes in two chunks
+        stream.write_all(format!("{}{}", head, &body[..20]).as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        stream.write_all(&body.as_bytes()[20..]).await.unwrap();
+        String::from_utf8_lossy(&request[..n]).into_owned()
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET /login.html HTTP/1.1\r\nHost: dvr\r\nAccept-Encoding: gzip\r\n\r\n").await.unwrap();
+    let body = "<html><body>var c=['bob','secret'];<script>submit('bob')</script></body></html>";
+    let expected = format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&received), expected);
+    assert_eq!(served.await.unwrap(), "GET /login.html HTTP/1.1\r\nHost: dvr\r\n\r\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Read a response with a content-length off `stream`, head and body.
+async fn read_response(stream: &mut TcpStream) -> (String, String) {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    let length = head.lines().find_map(|x| x.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+#[tokio::test]
+async fn the_password_logs_the_dvr_in() {
+    tcpforward::set_verbose(false);
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = server.local_addr().unwrap();
+    let mut builder = Builder::new().listen("127.0.0.1:0").remote(&remote.to_string());
+    builder.options().password = Some(String::from("se'cret\n[x]"));
+    let forwarder = builder.build().await.unwrap();
+    let addr = forwarder.local_addrs()[0];
+    tokio::spawn(forwarder.run());
+
+    let (constants, served) = tokio::sync::oneshot::channel::<String>();
+    tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let respond = |kind: &str, body: &str| format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nX-Frame-Options: DENY\r\nContent-Length: {}\r\n\r\n{}", kind, body.len(), body);
+        assert!(stream.read(&mut request).await.unwrap() > 0);
+        let app = r#"login:function(){s=o.getValue(),r=n.getValue()};Ext.define("widget.Button",{})"#;
+        stream.write_all(respond("application/javascript", app).as_bytes()).await.unwrap();
+        // the definition the first response brought in, as the DVR serves it
+        assert!(stream.read(&mut request).await.unwrap() > 0);
+        let defined = served.await.unwrap();
+        stream.write_all(respond("application/javascript; charset=utf-8", &defined).as_bytes()).await.unwrap();
+        // a video stream, rewriting it would wait for its end
+        assert!(stream.read(&mut request).await.unwrap() > 0);
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace\r\n\r\n--frame s=o.getValue(),r=n.getValue()").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET /app.js HTTP/1.1\r\n\r\n").await.unwrap();
+    let (head, body) = read_response(&mut stream).await;
+    assert!(!head.contains("X-Frame-Options"));
+    let (defined, rest) = body.split_once("Ext.define(\"widget.Button\"").unwrap();
+    assert_eq!(rest, ",{})");
+    let defined = defined.strip_prefix("login:function(){s='admin',r='se'cret\n[x]'};").unwrap();
+    assert!(defined.starts_with("Ext.define(\"data.Constants\""));
+    constants.send(defined.to_owned()).unwrap();
+
+    stream.write_all(b"GET /constants.js HTTP/1.1\r\n\r\n").await.unwrap();
+    let (_, body) = read_response(&mut stream).await;
+    assert_eq!(body, ";".repeat(defined.len()));
+
+    stream.write_all(b"GET /video HTTP/1.1\r\n\r\n").await.unwrap();
+    let expected = b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace\r\n\r\n--frame s=o.getValue(),r=n.getValue()";
+    let mut received = vec![0; expected.len()];
+    tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut received)).await.unwrap().unwrap();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+async fn metrics_count_the_forwarded_connections() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let metrics = free_addr();
+    let mut builder = forwarding_to(echo);
+    builder.options().metrics_listen = Some(metrics.to_string());
+    let (addr, registry) = start(builder).await;
+
+    assert_eq!(exchange(addr, b"ping").await, b"ping");
+    for _ in 0..50 {
+        if registry.connections().is_empty() {
+            break
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut stream = TcpStream::connect(metrics).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    let received = String::from_utf8(received).unwrap();
+    assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(received.contains("\ntcpforward_connections_accepted_total 1\n"));
+    assert!(received.contains("\ntcpforward_connections_active 0\n"));
+    assert!(received.contains(&format!("backend=\"{}\",direction=\"out\"}} 4\n", echo)));
+}
+
+#[tokio::test]
+async fn the_admin_api_lists_and_closes_connections() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let socket = std::env::temp_dir().join(format!("tcpforward-admin-{}.sock", std::process::id()));
+    let mut builder = forwarding_to(echo);
+    builder.options().admin_listen = Some(format!("unix:{}", socket.display()));
+    let (addr, _) = start(builder).await;
+    let admin = |request: String| {
+        let socket = socket.clone();
+        async move {
+            let mut stream = UnixStream::connect(socket).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            let received = String::from_utf8(received).unwrap();
+            received.split_once("\r\n\r\n").unwrap().1.to_owned()
+        }
+    };
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"hi").await.unwrap();
+    let mut echoed = [0; 2];
+    stream.read_exact(&mut echoed).await.unwrap();
+    let connections = admin(String::from("GET /connections HTTP/1.1\r\n\r\n")).await;
+    assert!(connections.contains(&format!("\"backend\":\"{}\",\"bytes_out\":2,\"bytes_in\":2", echo)));
+    assert_eq!(admin(String::from("GET /backends HTTP/1.1\r\n\r\n")).await, format!("[\"{}\"]", echo));
+
+    let id = connections.split_once("\"id\":").unwrap().1.split(',').next().unwrap().to_owned();
+    admin(format!("POST /connections/{}/close HTTP/1.1\r\n\r\n", id)).await;
+    let mut received = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut received)).await;
+    assert!(closed.is_ok() && received.is_empty());
+    std::fs::remove_file(&socket).unwrap();
+}
+
+/// Move the calling thread into a network namespace of its own with its
+/// loopback up, `false` without CAP_NET_ADMIN.
+#[cfg(target_os = "linux")]
+fn own_netns() -> bool {
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        return false
+    }
+    std::process::Command::new("ip").args(["link", "set", "lo", "up"]).status().is_ok_and(|x| x.success())
+}
+
+/// Run `iptables` in the namespace of the calling thread, `false` when it is
+/// not there.
+#[cfg(target_os = "linux")]
+fn iptables(args: &str) -> bool {
+    std::process::Command::new("iptables").args(args.split(' ')).status().is_ok_and(|x| x.success())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn transparent_modes_forward_diverted_clients_only() {
+    tcpforward::set_verbose(false);
+    // the test runs on one thread, what it starts shares its namespace
+    if !own_netns() {
+        eprintln!("skipped: a network namespace needs CAP_NET_ADMIN");
+        return
+    }
+    let echo = echo_server().await;
+
+    // a client that reaches the listener itself would make it dial itself
+    let mut builder = Builder::new().listen("127.0.0.1:0");
+    builder.options().tproxy = true;
+    let (addr, registry) = start(builder).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = Vec::new();
+    // dropped with the ping unread, which may reset it
+    let _ = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut received)).await.unwrap();
+    assert!(received.is_empty());
+    assert!(registry.recent(EventKind::Opened, 10).is_empty());
+
+    let mut builder = Builder::new().listen("127.0.0.1:0");
+    builder.options().transparent = true;
+    let (addr, _) = start(builder).await;
+    // only the clients from 127.0.0.3 are redirected, not the forwarder
+    if !iptables(&format!("-t nat -A OUTPUT -p tcp -s 127.0.0.3 -j REDIRECT --to-ports {}", addr.port())) {
+        eprintln!("skipped the redirect: iptables is missing");
+        return
+    }
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.3:0".parse().unwrap()).unwrap();
+    let mut stream = socket.connect(echo).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"ping");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received).await;
+    assert!(received.is_empty());
+}
+
+#[tokio::test]
+async fn socks5_connects_where_the_client_asks() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new().listen("127.0.0.1:0");
+    builder.options().socks5 = true;
+    builder.options().socks_user = Some(String::from("bob"));
+    builder.options().socks_password = Some(String::from("secret"));
+    let (addr, _) = start(builder).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 2]);
+    stream.write_all(b"\x01\x03bob\x06secret").await.unwrap();
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await.unwrap();
+    assert_eq!(status, [1, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(echo.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [5, 0, 0, 1]);
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+/// Read up to the end of a head off `stream`.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    String::from_utf8(head).unwrap()
+}
+
+#[tokio::test]
+async fn http_connect_tunnels_to_allowed_destinations() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut builder = Builder::new().listen("127.0.0.1:0");
+    builder.options().http_connect = true;
+    builder.options().allow.push(echo.to_string());
+    let (addr, _) = start(builder).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).await.unwrap();
+    assert_eq!(read_head(&mut stream).await, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\n\r\n").await.unwrap();
+    assert!(read_head(&mut stream).await.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+}
+
+#[tokio::test]
+async fn via_reaches_the_remote_through_a_proxy() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut proxy = Builder::new().listen("127.0.0.1:0");
+    proxy.options().socks5 = true;
+    let (proxy, proxy_registry) = start(proxy).await;
+    let mut builder = forwarding_to(echo);
+    builder.options().via = Some(format!("socks5://{}", proxy).parse().unwrap());
+    let (addr, _) = start(builder).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+    assert_eq!(proxy_registry.connections()[0].backend(), echo.to_string());
+}
+
+#[tokio::test]
+async fn a_reverse_tunnel_carries_clients_to_the_agent() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let tunnel = free_addr();
+    let mut server = Builder::new().listen("127.0.0.1:0");
+    server.options().tunnel_listen = Some(tunnel.to_string());
+    server.options().psk = Some(String::from("key"));
+    let (addr, _) = start(server).await;
+    let mut agent = Builder::new().remote(&echo.to_string());
+    agent.options().tunnel_server = Some(tunnel.to_string());
+    agent.options().psk = Some(String::from("key"));
+    tokio::spawn(agent.build().await.unwrap().run());
+
+    // clients are turned away until the agent is there
+    let mut received = Vec::new();
+    for _ in 0..50 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        received.clear();
+        if stream.read_to_end(&mut received).await.is_ok() && received == b"ping" {
+            break
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(received, b"ping");
+    assert_eq!(exchange(addr, b"pong").await, b"pong");
+}
+
+#[tokio::test]
+async fn mux_carries_connections_as_streams_of_one() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut accept = forwarding_to(echo);
+    accept.options().mux_accept = true;
+    let (accept, _) = start(accept).await;
+    let mut builder = forwarding_to(accept);
+    builder.options().mux = true;
+    let (addr, _) = start(builder).await;
+
+    let (one, two) = tokio::join!(exchange(addr, b"one"), exchange(addr, b"two"));
+    assert_eq!(one, b"one");
+    assert_eq!(two, b"two");
+    assert_eq!(exchange(addr, b"three").await, b"three");
+}
+
+#[tokio::test]
+async fn secure_links_need_the_same_key() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut local = forwarding_to(echo);
+    local.options().secure_local = true;
+    local.options().psk = Some(String::from("key"));
+    let (local, _) = start(local).await;
+    let secure = |psk: &str| {
+        let mut builder = forwarding_to(local);
+        builder.options().secure_remote = true;
+        builder.options().psk = Some(psk.to_owned());
+        start(builder)
+    };
+
+    let (addr, _) = secure("key").await;
+    assert_eq!(exchange(addr, b"ping").await, b"ping");
+
+    let (addr, _) = secure("other").await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received).await;
+    assert!(received.is_empty());
+}
+
+#[tokio::test]
+async fn compressed_links_carry_the_data_unchanged() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    let mut local = forwarding_to(echo);
+    local.options().compress_local = true;
+    let (local, _) = start(local).await;
+    let data = b"a rather repetitive line\n".repeat(2000);
+    for algorithm in ["zstd", "deflate"] {
+        let mut builder = forwarding_to(local);
+        builder.options().compress_remote = Some(algorithm.parse().unwrap());
+        let (addr, _) = start(builder).await;
+        assert_eq!(exchange(addr, &data).await, data);
+    }
+}
+
+#[tokio::test]
+async fn remote_names_fall_back_to_the_address_that_answers() {
+    tcpforward::set_verbose(false);
+    let echo = echo_server().await;
+    // localhost may well be ::1 first, where nothing listens
+    let (addr, _) = start(forwarding_to(format!("localhost:{}", echo.port()))).await;
+    assert_eq!(exchange(addr, b"ping").await, b"ping");
+}
+
+#[tokio::test]
+async fn unix_sockets_listen_and_forward() {
+    tcpforward::set_verbose(false);
+    let dir = std::env::temp_dir().join(format!("tcpforward-unix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (echo, listen) = (dir.join("echo.sock"), dir.join("listen.sock"));
+    let echoing = tokio::net::UnixListener::bind(&echo).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echoing.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                let _ = writer.shutdown().await;
+            });
+        }
+    });
+    // a socket left behind by a run that is gone
+    drop(std::os::unix::net::UnixListener::bind(&listen).unwrap());
+    let builder = || Builder::new().listen(&format!("unix:{}", listen.display())).remote(&format!("unix:{}", echo.display()));
+    let forwarder = builder().build().await.unwrap();
+    tokio::spawn(forwarder.run());
+
+    let mut stream = UnixStream::connect(&listen).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"ping");
+
+    // the socket of a running forwarder is not taken over
+    let e = builder().build().await.err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+    std::fs::remove_dir_all(&dir).unwrap();
+}